@@ -0,0 +1,254 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+use core::*;
+use core::alloc::*;
+use core::cell::Cell;
+use crate::*;
+
+pub const ARENA_CHUNK_SIZE : usize = 64 * 1024;
+
+// chunks are allocated as arrays of headers so the header itself is always aligned
+#[repr(C)]
+struct Chunk {
+    prev    : *mut Chunk,
+    count   : usize,    // size of the chunk in units of Chunk (header included)
+}
+
+impl Chunk {
+    fn start(this: *mut Chunk) -> usize { unsafe { this.add(1) as usize } }
+    fn end(this: *mut Chunk) -> usize { unsafe { this.add((*this).count) as usize } }
+}
+
+///
+/// Bump allocator: memory is handed out linearly from chunks obtained from `System`
+/// and only reclaimed all at once with `reset`, `scope` or when the arena is dropped.
+/// Collections allocate from it through `&Arena`, which borrows the arena for as
+/// long as they live.
+///
+pub struct Arena {
+    chunk_size  : usize,
+    head        : Cell<*mut Chunk>,
+    ptr         : Cell<usize>,
+    end         : Cell<usize>,
+    last        : Cell<usize>,      // start of the last allocation, can be grown or freed in place
+}
+
+// the arena owns its chunks so it can move to another thread, sharing it takes a lock (GlobalAdapter)
+unsafe impl Send for Arena {}
+
+impl Arena {
+    pub const fn new() -> Self { Self::with_chunk_size(ARENA_CHUNK_SIZE) }
+
+    pub const fn with_chunk_size(chunk_size: usize) -> Self {
+        Self {
+            chunk_size,
+            head    : Cell::new(ptr::null_mut()),
+            ptr     : Cell::new(0),
+            end     : Cell::new(0),
+            last    : Cell::new(0),
+        }
+    }
+
+    // reclaim everything, keeping the most recent chunk around for reuse
+    pub fn reset(&mut self) {
+        let head = self.head.get();
+        if head.is_null() { return }
+
+        unsafe {
+            Self::free_chunks((*head).prev, ptr::null_mut());
+            (*head).prev = ptr::null_mut();
+        }
+        self.ptr.set(Chunk::start(head));
+        self.end.set(Chunk::end(head));
+        self.last.set(0);
+    }
+
+    // run `f` and reclaim everything it allocated from the arena once it returns
+    pub fn scope<R, F: FnOnce(&Arena) -> R>(&mut self, f: F) -> R {
+        let head    = self.head.get();
+        let ptr     = self.ptr.get();
+        let end     = self.end.get();
+        let r = f(self);
+
+        unsafe { Self::free_chunks(self.head.get(), head) };
+        self.head.set(head);
+        self.ptr.set(ptr);
+        self.end.set(end);
+        self.last.set(0);
+        r
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        let mut count = 0;
+        let mut c = self.head.get();
+        while !c.is_null() {
+            count += 1;
+            c = unsafe { (*c).prev };
+        }
+        count
+    }
+
+    unsafe fn free_chunks(mut from: *mut Chunk, to: *mut Chunk) {
+        while from != to {
+            let prev = (*from).prev;
+            free_array_ptr(from, (*from).count);
+            from = prev;
+        }
+    }
+
+    fn new_chunk(&self, layout: Layout) -> bool {
+        let header  = mem::size_of::<Chunk>();
+        let bytes   = cmp::max(self.chunk_size, header + layout.size() + layout.align());
+        let count   = (bytes + header - 1) / header;
+        let chunk   = match unsafe { try_alloc_array::<Chunk>(count) } {
+            Ok(c) => c,
+            Err(_) => return false
+        };
+
+        unsafe { ptr::write(chunk, Chunk { prev: self.head.get(), count }) };
+        self.head.set(chunk);
+        self.ptr.set(Chunk::start(chunk));
+        self.end.set(Chunk::end(chunk));
+        true
+    }
+
+    fn bump(&self, layout: Layout) -> *mut u8 {
+        let start = (self.ptr.get() + layout.align() - 1) & !(layout.align() - 1);
+        if self.ptr.get() == 0 || start + layout.size() > self.end.get() {
+            if !self.new_chunk(layout) { return ptr::null_mut() }
+            return self.bump(layout);
+        }
+
+        self.ptr.set(start + layout.size());
+        self.last.set(start);
+        start as *mut u8
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { Self::free_chunks(self.head.get(), ptr::null_mut()) }
+    }
+}
+
+unsafe impl GlobalAlloc for &Arena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.bump(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        // only the last allocation can be given back, the rest waits for reset
+        if ptr as usize == self.last.get() {
+            self.ptr.set(ptr as usize);
+            self.last.set(0);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if ptr as usize == self.last.get() && ptr as usize + new_size <= self.end.get() {
+            self.ptr.set(ptr as usize + new_size);
+            ptr
+        } else {
+            realloc_fallback(self, ptr, layout, new_size)
+        }
+    }
+}
+
+unsafe impl UsableSize for &Arena {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_box_vec_string() {
+        let arena = Arena::with_chunk_size(256);
+        let b = Box::new_in(1234, &arena);
+        assert!(*b.as_ref() == 1234);
+
+        let mut v = Vec::new_in(&arena);
+        for i in 0..1000 {
+            v.push(i);
+        }
+        for i in 0..1000 {
+            assert!(v[i] == i);
+        }
+
+        let mut s = String::new_in(&arena);
+        s.push_str("hello ");
+        s.push_str("world");
+        assert!(s == "hello world");
+        assert!(arena.chunk_count() > 1);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let arena = Arena::new();
+        let a = &arena;
+        for i in 0..8 {
+            let align = 1 << i;
+            let p = unsafe { a.alloc(Layout::from_size_align(3, align).unwrap()) };
+            assert!((p as usize) & (align - 1) == 0);
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut arena = Arena::with_chunk_size(128);
+        for _ in 0..4 {
+            {
+                let mut v = Vec::new_in(&arena);
+                for i in 0..100 {
+                    v.push(String::from_in("frame", &arena));
+                    assert!(v[i] == "frame");
+                }
+            }
+            arena.reset();
+            assert!(arena.chunk_count() == 1);
+        }
+    }
+
+    #[test]
+    fn test_scope() {
+        let mut arena = Arena::with_chunk_size(128);
+        let _keep = unsafe { (&arena).alloc(Layout::new::<u64>()) };
+        let before = arena.chunk_count();
+        let sum = arena.scope(|a| {
+            let mut v = Vec::new_in(a);
+            for i in 0..100 {
+                v.push(i);
+            }
+            v.iter().sum::<usize>()
+        });
+        assert!(sum == 4950);
+        assert!(arena.chunk_count() == before);
+    }
+}
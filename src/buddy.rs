@@ -0,0 +1,402 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+//
+// Binary buddy allocator over a power-of-two region. Blocks are split in halves
+// on allocation and merged back with their buddy on free. The free state of every
+// block of every order is kept in a bitmap at the front of the region, the block
+// order itself is recomputed from the layout passed to dealloc.
+//
+use core::*;
+use core::alloc::*;
+use crate::spin::*;
+use crate::UsableSize;
+
+const MIN_BLOCK_LOG2    : usize = 5;
+pub const BUDDY_MIN_BLOCK : usize = 1 << MIN_BLOCK_LOG2;
+const MAX_ORDERS        : usize = usize::BITS as usize - MIN_BLOCK_LOG2;
+
+#[repr(C)]
+struct FreeBlock {
+    next    : *mut FreeBlock,
+    prev    : *mut FreeBlock,
+}
+
+struct State {
+    base        : usize,
+    orders      : usize,
+    free        : [*mut FreeBlock; MAX_ORDERS],
+    bitmap      : *mut usize,
+    free_bytes  : usize,
+    system_size : usize,    // region to request from the system on first use, 0 if none
+    owned       : bool,
+}
+
+unsafe impl Send for State {}
+
+#[inline]
+fn fls(x: usize) -> usize { usize::BITS as usize - 1 - x.leading_zeros() as usize }
+
+impl State {
+    const fn new(system_size: usize) -> Self {
+        Self {
+            base        : 0,
+            orders      : 0,
+            free        : [ptr::null_mut(); MAX_ORDERS],
+            bitmap      : ptr::null_mut(),
+            free_bytes  : 0,
+            system_size,
+            owned       : false,
+        }
+    }
+
+    #[inline]
+    fn block_size(k: usize) -> usize { BUDDY_MIN_BLOCK << k }
+
+    #[inline]
+    fn region_size(&self) -> usize { if self.orders == 0 { 0 } else { Self::block_size(self.orders - 1) } }
+
+    // bits of order k start after all the bits of the smaller orders
+    #[inline]
+    fn bit(&self, k: usize, offset: usize) -> usize {
+        let l = self.orders - 1;
+        (1 << (l + 1)) - (1 << (l + 1 - k)) + (offset >> (MIN_BLOCK_LOG2 + k))
+    }
+
+    #[inline]
+    unsafe fn is_free(&self, k: usize, offset: usize) -> bool {
+        let b = self.bit(k, offset);
+        *self.bitmap.add(b / usize::BITS as usize) & (1 << (b % usize::BITS as usize)) != 0
+    }
+
+    #[inline]
+    unsafe fn set_free(&mut self, k: usize, offset: usize, free: bool) {
+        let b = self.bit(k, offset);
+        let w = self.bitmap.add(b / usize::BITS as usize);
+        if free { *w |= 1 << (b % usize::BITS as usize) } else { *w &= !(1 << (b % usize::BITS as usize)) }
+    }
+
+    unsafe fn push(&mut self, k: usize, offset: usize) {
+        let node = (self.base + offset) as *mut FreeBlock;
+        let head = self.free[k];
+        (*node).next = head;
+        (*node).prev = ptr::null_mut();
+        if !head.is_null() { (*head).prev = node }
+        self.free[k] = node;
+        self.set_free(k, offset, true);
+    }
+
+    unsafe fn remove(&mut self, k: usize, offset: usize) {
+        let node = (self.base + offset) as *mut FreeBlock;
+        let next = (*node).next;
+        let prev = (*node).prev;
+        if !next.is_null() { (*next).prev = prev }
+        if prev.is_null() { self.free[k] = next } else { (*prev).next = next }
+        self.set_free(k, offset, false);
+    }
+
+    unsafe fn init(&mut self, start: usize, len: usize) {
+        let base = (start + BUDDY_MIN_BLOCK - 1) & !(BUDDY_MIN_BLOCK - 1);
+        if start + len <= base + 2 * BUDDY_MIN_BLOCK { return }
+        let size_log2 = fls(start + len - base);
+
+        self.base   = base;
+        self.orders = size_log2 - MIN_BLOCK_LOG2 + 1;
+        self.free   = [ptr::null_mut(); MAX_ORDERS];
+
+        // the bitmap occupies the front of the region, those blocks are never free
+        let bits    = (1 << self.orders) - 1;
+        let words   = bits / usize::BITS as usize + 1;
+        self.bitmap = base as *mut usize;
+        ptr::write_bytes(self.bitmap, 0, words);
+
+        let size    = self.region_size();
+        let mut offset = (words * mem::size_of::<usize>() + BUDDY_MIN_BLOCK - 1) & !(BUDDY_MIN_BLOCK - 1);
+        self.free_bytes = 0;
+        while offset < size {
+            let mut k = cmp::min(offset.trailing_zeros() as usize - MIN_BLOCK_LOG2, self.orders - 1);
+            while offset + Self::block_size(k) > size { k -= 1 }
+            self.push(k, offset);
+            self.free_bytes += Self::block_size(k);
+            offset += Self::block_size(k);
+        }
+    }
+
+    unsafe fn init_from_system(&mut self) {
+        let size = self.system_size.next_power_of_two();
+        self.system_size = 0;
+        let p = crate::os::map_pages(size);
+        if p.is_null() { return }
+        self.owned = true;
+        self.init(p as usize, size);
+    }
+
+    fn order(&self, layout: &Layout) -> Option<usize> {
+        let size = cmp::max(cmp::max(layout.size(), layout.align()), BUDDY_MIN_BLOCK);
+        let k = fls(size.checked_next_power_of_two()?) - MIN_BLOCK_LOG2;
+        if k < self.orders && self.base & (layout.align() - 1) == 0 { Some(k) } else { None }
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if self.orders == 0 && self.system_size != 0 { self.init_from_system() }
+
+        let k = match self.order(&layout) {
+            Some(k) => k,
+            None => return ptr::null_mut()
+        };
+
+        let mut j = k;
+        while j < self.orders && self.free[j].is_null() { j += 1 }
+        if j == self.orders { return ptr::null_mut() }
+
+        let offset = self.free[j] as usize - self.base;
+        self.remove(j, offset);
+        while j > k {
+            j -= 1;
+            self.push(j, offset + Self::block_size(j));
+        }
+
+        self.free_bytes -= Self::block_size(k);
+        (self.base + offset) as *mut u8
+    }
+
+    unsafe fn release(&mut self, offset: usize, k: usize) {
+        self.free_bytes += Self::block_size(k);
+
+        let mut k = k;
+        let mut offset = offset;
+        while k + 1 < self.orders {
+            let buddy = offset ^ Self::block_size(k);
+            if !self.is_free(k, buddy) { break }
+            self.remove(k, buddy);
+            offset = cmp::min(offset, buddy);
+            k += 1;
+        }
+        self.push(k, offset);
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Some(k) = self.order(&layout) {
+            self.release(ptr as usize - self.base, k);
+        }
+    }
+
+    // shrink in place by giving back the upper halves, null if the block has to grow
+    unsafe fn resize(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old = match self.order(&layout) {
+            Some(k) => k,
+            None => return ptr::null_mut()
+        };
+        let new = match self.order(&Layout::from_size_align_unchecked(new_size, layout.align())) {
+            Some(k) if k <= old => k,
+            _ => return ptr::null_mut()
+        };
+
+        let offset = ptr as usize - self.base;
+        for j in (new..old).rev() {
+            self.release(offset + Self::block_size(j), j);
+        }
+        ptr
+    }
+
+    fn largest_free(&self) -> usize {
+        (0..self.orders).rev().find(|k| !self.free[*k].is_null()).map_or(0, Self::block_size)
+    }
+}
+
+///
+/// Buddy allocator, either over a caller provided region or over a region
+/// mapped from the OS the first time it allocates.
+///
+pub struct Buddy {
+    state   : SpinLock<State>,
+}
+
+impl Buddy {
+    pub const fn new() -> Self { Self { state: SpinLock::new(State::new(0)) } }
+
+    // lazily reserve `size` (rounded up to a power of two) from the system allocator
+    pub const fn from_system(size: usize) -> Self { Self { state: SpinLock::new(State::new(size)) } }
+
+    pub fn with_region(mem: &'static mut [u8]) -> Self {
+        let b = Self::new();
+        b.init_region(mem);
+        b
+    }
+
+    pub fn init_region(&self, mem: &'static mut [u8]) {
+        let mut s = self.state.lock();
+        assert!(s.orders == 0, "buddy region already initialized");
+        s.system_size = 0;
+        unsafe { s.init(mem.as_mut_ptr() as usize, mem.len()) }
+    }
+
+    // size of the largest block that can currently be allocated
+    pub fn largest_free(&self) -> usize { self.state.lock().largest_free() }
+
+    pub fn free_bytes(&self) -> usize { self.state.lock().free_bytes }
+
+    pub fn region_size(&self) -> usize { self.state.lock().region_size() }
+}
+
+impl Default for Buddy {
+    fn default() -> Self { Self::new() }
+}
+
+impl Drop for Buddy {
+    fn drop(&mut self) {
+        let s = self.state.lock();
+        if s.owned {
+            unsafe { crate::os::unmap_pages(s.base as *mut u8, s.region_size()) }
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Buddy {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.state.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.state.lock().dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = self.state.lock().resize(ptr, layout, new_size);
+        if !p.is_null() { return p }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+unsafe impl GlobalAlloc for &Buddy {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl UsableSize for Buddy {
+    unsafe fn usable_size(&self, _ptr: *mut u8, layout: Layout) -> usize {
+        match self.state.lock().order(&layout) {
+            Some(k) => State::block_size(k),
+            None => layout.size(),
+        }
+    }
+}
+
+unsafe impl UsableSize for &Buddy {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { (*self).usable_size(ptr, layout) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    extern crate std;
+
+    static mut BUFFER : [u8; 64 * 1024] = [0; 64 * 1024];
+
+    #[test]
+    fn test_static_buffer() {
+        let buddy = Buddy::with_region(unsafe { &mut *ptr::addr_of_mut!(BUFFER) });
+        let mut v = Vec::new_in(&buddy);
+        for i in 0..1000 {
+            v.push(i);
+        }
+        assert!(v[999] == 999);
+    }
+
+    #[test]
+    fn test_split_merge() {
+        let buddy = Buddy::from_system(1 << 20);
+        let a = &buddy;
+        let l = Layout::from_size_align(100, 8).unwrap();
+        let p0 = unsafe { a.alloc(l) };
+        assert!(buddy.region_size() == 1 << 20);
+
+        let free = buddy.free_bytes();
+        let largest = buddy.largest_free();
+        assert!(largest == 1 << 19);
+
+        let mut ptrs = std::vec::Vec::new();
+        for _ in 0..5000 {
+            let p = unsafe { a.alloc(l) };
+            assert!(!p.is_null());
+            ptrs.push(p);
+        }
+        assert!(buddy.largest_free() < largest);
+
+        for p in ptrs {
+            unsafe { a.dealloc(p, l) };
+        }
+        assert!(buddy.free_bytes() == free);
+        assert!(buddy.largest_free() == largest);
+
+        unsafe { a.dealloc(p0, l) };
+    }
+
+    #[test]
+    fn test_alignment_realloc() {
+        let buddy = Buddy::from_system(1 << 16);
+        let a = &buddy;
+        for i in 0..12 {
+            let align = 1 << i;
+            let p = unsafe { a.alloc(Layout::from_size_align(24, align).unwrap()) };
+            assert!(!p.is_null());
+            assert!((p as usize) & (align - 1) == 0);
+        }
+
+        let l = Layout::from_size_align(1024, 8).unwrap();
+        let free = buddy.free_bytes();
+        unsafe {
+            let p = a.alloc(l);
+            let q = a.realloc(p, l, 100);
+            assert!(p == q);
+            assert!(buddy.free_bytes() == free - 128);
+            a.dealloc(q, Layout::from_size_align(100, 8).unwrap());
+        }
+        assert!(buddy.free_bytes() == free);
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        let buddy = Buddy::from_system(4096);
+        let a = &buddy;
+        assert!(unsafe { a.alloc(Layout::from_size_align(4096, 8).unwrap()) }.is_null());
+        assert!(!unsafe { a.alloc(Layout::from_size_align(1024, 8).unwrap()) }.is_null());
+    }
+}
@@ -0,0 +1,196 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::alloc::*;
+use core::sync::atomic::*;
+use crate::*;
+
+///
+/// Wrapper capping the live bytes allocated through it: requests that would go over
+/// the limit get null, which `try_push`, `try_set`... report as `OutOfMemory`.
+/// Budgets nest: `sub_budget` allocates from its parent, a request has to fit in
+/// both.
+///
+pub struct BudgetAlloc<A: GlobalAlloc = System> {
+    inner   : A,
+    limit   : AtomicUsize,
+    used    : AtomicUsize,
+    peak    : AtomicUsize,
+    refused : AtomicUsize,
+}
+
+impl BudgetAlloc {
+    pub const fn new(limit: usize) -> Self { Self::new_in(limit, System) }
+}
+
+impl<A: GlobalAlloc> BudgetAlloc<A> {
+    pub const fn new_in(limit: usize, inner: A) -> Self {
+        Self {
+            inner,
+            limit   : AtomicUsize::new(limit),
+            used    : AtomicUsize::new(0),
+            peak    : AtomicUsize::new(0),
+            refused : AtomicUsize::new(0),
+        }
+    }
+
+    pub fn sub_budget(&self, limit: usize) -> BudgetAlloc<&Self> { BudgetAlloc::new_in(limit, self) }
+
+    pub fn inner(&self) -> &A { &self.inner }
+
+    pub fn limit(&self) -> usize { self.limit.load(Ordering::Relaxed) }
+
+    // lowering the limit under the bytes in use only refuses the next requests
+    pub fn set_limit(&self, limit: usize) { self.limit.store(limit, Ordering::Relaxed) }
+
+    pub fn used(&self) -> usize { self.used.load(Ordering::Relaxed) }
+    pub fn peak(&self) -> usize { self.peak.load(Ordering::Relaxed) }
+    pub fn available(&self) -> usize { self.limit().saturating_sub(self.used()) }
+
+    // requests turned down because of this budget
+    pub fn refused(&self) -> usize { self.refused.load(Ordering::Relaxed) }
+
+    fn charge(&self, size: usize) -> bool {
+        let limit = self.limit();
+        let r = self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            used.checked_add(size).filter(|&u| u <= limit)
+        });
+        match r {
+            Ok(used) => {
+                self.peak.fetch_max(used + size, Ordering::Relaxed);
+                true
+            },
+            Err(_) => {
+                self.refused.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for BudgetAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !self.charge(layout.size()) { return core::ptr::null_mut() }
+        let p = self.inner.alloc(layout);
+        if p.is_null() { self.release(layout.size()) }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if !self.charge(layout.size()) { return core::ptr::null_mut() }
+        let p = self.inner.alloc_zeroed(layout);
+        if p.is_null() { self.release(layout.size()) }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.release(layout.size());
+    }
+
+    // growth is charged up front, shrinking only released once it succeeded
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size > layout.size() {
+            let grow = new_size - layout.size();
+            if !self.charge(grow) { return core::ptr::null_mut() }
+            let p = self.inner.realloc(ptr, layout, new_size);
+            if p.is_null() { self.release(grow) }
+            p
+        } else {
+            let p = self.inner.realloc(ptr, layout, new_size);
+            if !p.is_null() { self.release(layout.size() - new_size) }
+            p
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for &BudgetAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl<A: GlobalAlloc> UsableSize for BudgetAlloc<A> {}
+unsafe impl<A: GlobalAlloc> UsableSize for &BudgetAlloc<A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        let budget = BudgetAlloc::new(1000);
+        {
+            let mut v = Vec::new_in(&budget);
+            let mut pushed = 0;
+            while v.try_push(0u64).is_ok() {
+                pushed += 1;
+            }
+            assert!(pushed == 64);
+            assert!(v.try_push(0u64) == Err((0, crate::AllocError::OutOfMemory(Layout::array::<u64>(128).unwrap()))));
+            assert!(budget.used() == 512 && budget.refused() == 2);
+
+            let mut s = String::new_in(&budget);
+            assert!(s.try_push_str("fits").is_ok());
+        }
+        assert!(budget.used() == 0);
+        assert!(budget.peak() <= 1000);
+    }
+
+    #[test]
+    fn test_sub_budget() {
+        let parent = BudgetAlloc::new(4096);
+        let plugin = parent.sub_budget(1024);
+        {
+            let mut hm = HashMap::new_in(&plugin);
+            let mut i = 0;
+            while hm.try_set(i, i).is_ok() {
+                i += 1;
+            }
+            assert!(plugin.used() <= 1024);
+            assert!(parent.used() == plugin.used());
+        }
+        assert!(parent.used() == 0);
+
+        // the parent caps the child even when the child's own limit is higher
+        parent.set_limit(256);
+        plugin.set_limit(1 << 20);
+        let mut v = Vec::<u8, _>::new_in(&plugin);
+        assert!(v.try_reserve(1024).is_err());
+        assert!(v.try_reserve(128).is_ok());
+        assert!(parent.refused() == 1);
+    }
+}
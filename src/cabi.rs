@@ -0,0 +1,255 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+//
+// C allocation API over `SizeClassAlloc`, for building the crate as a malloc
+// replacement:
+//
+//   cargo rustc --release --lib --features malloc --crate-type cdylib
+//   LD_PRELOAD=target/release/librs_alloc.so ./program
+//
+// C frees without a size, so every block starts with a header holding its layout.
+// The header sits right before the returned pointer, at the end of `pad` bytes kept
+// in front of the payload: 16 bytes, or the alignment for over-aligned blocks.
+// Nothing here may call into libc's allocator, the symbols below replace it.
+//
+use core::*;
+use core::alloc::*;
+use crate::*;
+
+static HEAP : SizeClassAlloc = SizeClassAlloc::new();
+
+const HEADER : usize = 16;
+
+#[repr(C)]
+struct Header {
+    size    : usize,    // size of the whole block, pad included
+    align   : usize,
+}
+
+#[inline]
+fn pad(align: usize) -> usize { cmp::max(align, HEADER) }
+
+#[inline]
+unsafe fn header(p: *mut u8) -> *mut Header { p.sub(HEADER) as *mut Header }
+
+#[inline]
+unsafe fn set_errno(e: i32) { *libc::__errno_location() = e }
+
+unsafe fn alloc_aligned(size: usize, align: usize, zeroed: bool) -> *mut u8 {
+    let align = cmp::max(align, HEADER);
+    let layout = match size.checked_add(pad(align)).map(|s| Layout::from_size_align(s, align)) {
+        Some(Ok(l)) => l,
+        _ => { set_errno(libc::ENOMEM); return ptr::null_mut() }
+    };
+
+    let base = if zeroed { HEAP.alloc_zeroed(layout) } else { HEAP.alloc(layout) };
+    if base.is_null() { set_errno(libc::ENOMEM); return base }
+    let p = base.add(pad(align));
+    ptr::write(header(p), Header { size: layout.size(), align });
+    p
+}
+
+unsafe fn block(p: *mut u8) -> (*mut u8, Layout) {
+    let h = ptr::read(header(p));
+    (p.sub(pad(h.align)), Layout::from_size_align_unchecked(h.size, h.align))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut u8 {
+    alloc_aligned(size, HEADER, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(count: usize, size: usize) -> *mut u8 {
+    match count.checked_mul(size) {
+        Some(total) => alloc_aligned(total, HEADER, true),
+        None => { set_errno(libc::ENOMEM); ptr::null_mut() }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free(p: *mut u8) {
+    if p.is_null() { return }
+    let (base, layout) = block(p);
+    HEAP.dealloc(base, layout)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn realloc(p: *mut u8, size: usize) -> *mut u8 {
+    if p.is_null() { return malloc(size) }
+    if size == 0 {
+        free(p);
+        return ptr::null_mut()
+    }
+
+    // everything malloc_usable_size reported may be in use and has to move along
+    let (base, layout) = block(p);
+    let layout = Layout::from_size_align_unchecked(HEAP.usable_size(base, layout), layout.align());
+    let pad = pad(layout.align());
+    let new_size = match size.checked_add(pad) {
+        Some(s) if s <= isize::MAX as usize => s,
+        _ => { set_errno(libc::ENOMEM); return ptr::null_mut() }
+    };
+    let new_base = HEAP.realloc(base, layout, new_size);
+    if new_base.is_null() { set_errno(libc::ENOMEM); return new_base }
+    let p = new_base.add(pad);
+    (*header(p)).size = new_size;
+    p
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(out: *mut *mut u8, align: usize, size: usize) -> i32 {
+    if !align.is_power_of_two() || align < mem::size_of::<usize>() { return libc::EINVAL }
+    let p = alloc_aligned(size, align, false);
+    if p.is_null() { return libc::ENOMEM }
+    *out = p;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: usize, size: usize) -> *mut u8 {
+    if !align.is_power_of_two() { set_errno(libc::EINVAL); return ptr::null_mut() }
+    alloc_aligned(size, align, false)
+}
+
+// not asked for by most programs, but glibc's versions would hand out blocks `free` can't take
+#[no_mangle]
+pub unsafe extern "C" fn memalign(align: usize, size: usize) -> *mut u8 {
+    aligned_alloc(align, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: usize) -> *mut u8 {
+    alloc_aligned(size, 4096, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: usize) -> *mut u8 {
+    match size.checked_add(4095) {
+        Some(s) => alloc_aligned(s & !4095, 4096, false),
+        None => { set_errno(libc::ENOMEM); ptr::null_mut() }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(p: *mut u8) -> usize {
+    if p.is_null() { return 0 }
+    let (base, layout) = block(p);
+    HEAP.usable_size(base, layout) - pad(layout.align())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    extern crate std;
+    use std::process::Command;
+
+    const PROGRAM : &str = r#"
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <malloc.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define CHECK(c) do { if (!(c)) { printf("failed: %s\n", #c); return 1; } } while (0)
+
+int main(void) {
+    Dl_info info;
+    CHECK(dladdr((void *)malloc, &info) && strstr(info.dli_fname, "librs_alloc"));
+
+    char *p = malloc(100);
+    memset(p, 7, 100);
+    CHECK(malloc_usable_size(p) >= 100);
+    p = realloc(p, 100000);
+    CHECK(p[99] == 7);
+    free(p);
+
+    size_t n = malloc_usable_size(p = malloc(20));
+    memset(p, 9, n);
+    p = realloc(p, 4 * n);
+    CHECK(p[n - 1] == 9);
+    free(p);
+
+    int *z = calloc(1000, sizeof(int));
+    for (int i = 0; i < 1000; i++) CHECK(z[i] == 0);
+    free(z);
+
+    void *a = NULL;
+    CHECK(posix_memalign(&a, 4096, 10) == 0 && ((uintptr_t)a & 4095) == 0);
+    a = realloc(a, 20000);
+    CHECK(((uintptr_t)a & 4095) == 0);
+    free(a);
+    CHECK(posix_memalign(&a, 3, 10) != 0);
+
+    void *b = aligned_alloc(64, 640);
+    CHECK(((uintptr_t)b & 63) == 0);
+    free(b);
+
+    char *s = strdup("through libc");
+    CHECK(strcmp(s, "through libc") == 0);
+    free(s);
+
+    printf("ok\n");
+    return 0;
+}
+"#;
+
+    // slow and needs a C compiler: cargo test --features malloc -- --ignored
+    #[test]
+    #[ignore]
+    fn test_preload() {
+        // the outer target dir is locked while the tests run
+        let root = env!("CARGO_MANIFEST_DIR");
+        let target = std::format!("{}/target/preload", root);
+        std::fs::create_dir_all(&target).unwrap();
+
+        let built = Command::new(env!("CARGO"))
+            .args(["rustc", "--release", "--lib", "--features", "malloc", "--crate-type", "cdylib"])
+            .env("CARGO_TARGET_DIR", &target)
+            .current_dir(root)
+            .status().unwrap();
+        assert!(built.success());
+
+        let src = std::format!("{}/preload.c", target);
+        let exe = std::format!("{}/preload", target);
+        std::fs::write(&src, PROGRAM).unwrap();
+        let compiled = Command::new("cc").args([src.as_str(), "-o", exe.as_str(), "-ldl"]).status().unwrap();
+        assert!(compiled.success());
+
+        let out = Command::new(&exe)
+            .env("LD_PRELOAD", std::format!("{}/release/librs_alloc.so", target))
+            .output().unwrap();
+        let stdout = std::string::String::from_utf8_lossy(&out.stdout);
+        assert!(out.status.success() && stdout.trim() == "ok", "{}", stdout);
+    }
+}
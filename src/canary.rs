@@ -0,0 +1,238 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use core::sync::atomic::*;
+use crate::os::System;
+use crate::UsableSize;
+
+pub const CANARY_SIZE   : usize = 16;
+pub const CANARY_BYTE   : u8 = 0xfd;
+pub const FRESH_BYTE    : u8 = 0xcd;
+pub const FREED_BYTE    : u8 = 0xdd;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Corruption {
+    pub ptr     : *mut u8,
+    pub layout  : Layout,
+    pub front   : bool,     // the canary before the block was overwritten
+    pub back    : bool,     // the canary after the block was overwritten
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrupted block {:p} ({} bytes, align {}):", self.ptr, self.layout.size(), self.layout.align())?;
+        if self.front { write!(f, " underflow")? }
+        if self.back { write!(f, " overflow")? }
+        Ok(())
+    }
+}
+
+// unwinding out of dealloc is undefined behavior, so report and abort instead
+fn abort_on_corruption(c: &Corruption) {
+    crate::os::abort_with(format_args!("{}\n", c))
+}
+
+///
+/// Wrapper surrounding every block with canary bytes that are checked when the
+/// block is freed or reallocated. Fresh blocks are filled with `FRESH_BYTE` and
+/// freed ones with `FREED_BYTE` to make uninitialized reads and use after free
+/// stand out.
+///
+pub struct CanaryAlloc<A: GlobalAlloc = System> {
+    inner       : A,
+    handler     : fn(&Corruption),
+    corruptions : AtomicUsize,
+}
+
+impl CanaryAlloc {
+    pub const fn new() -> Self { Self::new_in(System) }
+}
+
+impl Default for CanaryAlloc {
+    fn default() -> Self { Self::new() }
+}
+
+impl<A: GlobalAlloc> CanaryAlloc<A> {
+    // aborts on the first corrupted block
+    pub const fn new_in(inner: A) -> Self { Self::with_handler(inner, abort_on_corruption) }
+
+    pub const fn with_handler(inner: A, handler: fn(&Corruption)) -> Self {
+        Self { inner, handler, corruptions: AtomicUsize::new(0) }
+    }
+
+    pub fn inner(&self) -> &A { &self.inner }
+
+    pub fn corruptions(&self) -> usize { self.corruptions.load(Ordering::SeqCst) }
+
+    #[inline]
+    fn front(layout: &Layout) -> usize { cmp::max(CANARY_SIZE, layout.align()) }
+
+    fn padded(layout: &Layout) -> Option<Layout> {
+        let size = layout.size().checked_add(Self::front(layout) + CANARY_SIZE)?;
+        Layout::from_size_align(size, layout.align()).ok()
+    }
+
+    unsafe fn intact(p: *const u8) -> bool {
+        slice::from_raw_parts(p, CANARY_SIZE).iter().all(|b| *b == CANARY_BYTE)
+    }
+
+    unsafe fn check(&self, ptr: *mut u8, layout: &Layout) {
+        let front = !Self::intact(ptr.sub(CANARY_SIZE));
+        let back = !Self::intact(ptr.add(layout.size()));
+        if front || back {
+            self.corruptions.fetch_add(1, Ordering::SeqCst);
+            (self.handler)(&Corruption { ptr, layout: *layout, front, back });
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CanaryAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let padded = match Self::padded(&layout) {
+            Some(l) => l,
+            None => return ptr::null_mut()
+        };
+        let base = self.inner.alloc(padded);
+        if base.is_null() { return base }
+
+        let p = base.add(Self::front(&layout));
+        ptr::write_bytes(p.sub(CANARY_SIZE), CANARY_BYTE, CANARY_SIZE);
+        ptr::write_bytes(p, FRESH_BYTE, layout.size());
+        ptr::write_bytes(p.add(layout.size()), CANARY_BYTE, CANARY_SIZE);
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.check(ptr, &layout);
+        ptr::write_bytes(ptr, FREED_BYTE, layout.size());
+        let padded = Layout::from_size_align_unchecked(layout.size() + Self::front(&layout) + CANARY_SIZE, layout.align());
+        self.inner.dealloc(ptr.sub(Self::front(&layout)), padded)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.check(ptr, &layout);
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for &CanaryAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+// the canary sits right after the requested size
+unsafe impl<A: GlobalAlloc> UsableSize for CanaryAlloc<A> {}
+unsafe impl<A: GlobalAlloc> UsableSize for &CanaryAlloc<A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    static LAST_SIZE : AtomicUsize = AtomicUsize::new(0);
+
+    fn record(c: &Corruption) {
+        LAST_SIZE.store(c.layout.size(), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_clean() {
+        let canary = CanaryAlloc::new();
+        {
+            let mut v = Vec::new_in(&canary);
+            for i in 0..1000 {
+                v.push(i);
+            }
+            let mut hm = HashMap::new_in(&canary);
+            for i in 0..100 {
+                hm.set(i, String::from_in("value", &canary));
+            }
+        }
+        assert!(canary.corruptions() == 0);
+    }
+
+    #[test]
+    fn test_patterns() {
+        let canary = CanaryAlloc::new();
+        let a = &canary;
+        let l = Layout::from_size_align(32, 64).unwrap();
+        unsafe {
+            let p = a.alloc(l);
+            assert!((p as usize) & 63 == 0);
+            assert!(slice::from_raw_parts(p, 32).iter().all(|b| *b == FRESH_BYTE));
+            let q = a.realloc(p, l, 64);
+            assert!(slice::from_raw_parts(q, 32).iter().all(|b| *b == FRESH_BYTE));
+            a.dealloc(q, Layout::from_size_align(64, 64).unwrap());
+        }
+        assert!(canary.corruptions() == 0);
+    }
+
+    #[test]
+    fn test_overflow_detected() {
+        let canary = CanaryAlloc::with_handler(System, record);
+        let a = &canary;
+        let l = Layout::from_size_align(24, 8).unwrap();
+        unsafe {
+            let p = a.alloc(l);
+            *p.add(24) = 0;
+            a.dealloc(p, l);
+        }
+        assert!(canary.corruptions() == 1);
+        assert!(LAST_SIZE.load(Ordering::SeqCst) == 24);
+    }
+
+    #[test]
+    fn test_underflow_aborts() {
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                let canary = CanaryAlloc::new();
+                let a = &canary;
+                let l = Layout::new::<u64>();
+                let p = a.alloc(l);
+                *p.sub(1) = 0;
+                a.dealloc(p, l);
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            libc::waitpid(pid, &mut status, 0);
+            assert!(libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGABRT);
+        }
+    }
+}
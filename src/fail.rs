@@ -0,0 +1,231 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::alloc::*;
+use core::sync::atomic::*;
+use crate::*;
+
+// which requests fail, allocations are counted from 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailPolicy {
+    Never,
+    Nth(usize),
+    EveryNth(usize),
+    Above(usize),
+    Random { seed: u64, percent: u32 },
+}
+
+///
+/// Fault injection wrapper for testing out of memory paths: requests picked by the
+/// policy get null instead of reaching the inner allocator. Allocations, zeroed
+/// allocations and growing reallocations are counted, frees and shrinks never fail.
+///
+pub struct FailAlloc<A: GlobalAlloc = System> {
+    inner       : A,
+    policy      : FailPolicy,
+    count       : AtomicUsize,
+    failures    : AtomicUsize,
+    rng         : AtomicU64,
+}
+
+impl FailAlloc {
+    pub const fn new(policy: FailPolicy) -> Self { Self::new_in(policy, System) }
+}
+
+impl<A: GlobalAlloc> FailAlloc<A> {
+    pub const fn new_in(policy: FailPolicy, inner: A) -> Self {
+        let seed = match policy { FailPolicy::Random { seed, .. } => seed, _ => 0 };
+        Self {
+            inner,
+            policy,
+            count       : AtomicUsize::new(0),
+            failures    : AtomicUsize::new(0),
+            // xorshift gets stuck on 0
+            rng         : AtomicU64::new(if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed }),
+        }
+    }
+
+    pub fn inner(&self) -> &A { &self.inner }
+    pub fn policy(&self) -> FailPolicy { self.policy }
+
+    // allocation requests seen so far, failed ones included
+    pub fn allocations(&self) -> usize { self.count.load(Ordering::Relaxed) }
+    pub fn failures(&self) -> usize { self.failures.load(Ordering::Relaxed) }
+
+    fn random(&self) -> u64 {
+        let step = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let prev = self.rng.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x))).unwrap();
+        step(prev)
+    }
+
+    fn should_fail(&self, size: usize) -> bool {
+        let n = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        let fail = match self.policy {
+            FailPolicy::Never => false,
+            FailPolicy::Nth(k) => n == k,
+            FailPolicy::EveryNth(k) => n % k == 0,
+            FailPolicy::Above(limit) => size > limit,
+            FailPolicy::Random { percent, .. } => self.random() % 100 < percent as u64,
+        };
+        if fail { self.failures.fetch_add(1, Ordering::Relaxed); }
+        fail
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for FailAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.should_fail(layout.size()) { return core::ptr::null_mut() }
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if self.should_fail(layout.size()) { return core::ptr::null_mut() }
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size > layout.size() && self.should_fail(new_size) { return core::ptr::null_mut() }
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for &FailAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl<A: GlobalAlloc> UsableSize for FailAlloc<A> {}
+unsafe impl<A: GlobalAlloc> UsableSize for &FailAlloc<A> {}
+
+//
+// Run `f` once for every allocation it makes, failing the first one, then the
+// second... until a run goes through without any failure. `f` checks its own
+// data is consistent after a failure, the sweep panics if a run leaks. Returns
+// the number of runs.
+//
+pub fn fail_sweep<F: FnMut(&FailAlloc<&StatsAlloc>)>(mut f: F) -> usize {
+    let mut n = 1;
+    loop {
+        let stats = StatsAlloc::new();
+        let fail = FailAlloc::new_in(FailPolicy::Nth(n), &stats);
+        f(&fail);
+        let live = stats.stats().live_bytes;
+        if live != 0 { panic!("failing allocation {} leaked {} bytes", n, live) }
+        if fail.failures() == 0 { return n }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(fail: &FailAlloc, count: usize, size: usize) -> usize {
+        let mut failed = 0;
+        let l = Layout::from_size_align(size, 8).unwrap();
+        for _ in 0..count {
+            let p = unsafe { fail.alloc(l) };
+            if p.is_null() { failed += 1 } else { unsafe { fail.dealloc(p, l) } }
+        }
+        failed
+    }
+
+    #[test]
+    fn test_policies() {
+        assert!(run(&FailAlloc::new(FailPolicy::Never), 100, 8) == 0);
+        assert!(run(&FailAlloc::new(FailPolicy::Nth(10)), 100, 8) == 1);
+        assert!(run(&FailAlloc::new(FailPolicy::EveryNth(10)), 100, 8) == 10);
+        assert!(run(&FailAlloc::new(FailPolicy::Above(64)), 10, 64) == 0);
+        assert!(run(&FailAlloc::new(FailPolicy::Above(64)), 10, 65) == 10);
+
+        let random = |seed| run(&FailAlloc::new(FailPolicy::Random { seed, percent: 25 }), 1000, 8);
+        let failed = random(42);
+        assert!(failed > 150 && failed < 350);
+        assert!(random(42) == failed);
+    }
+
+    #[test]
+    fn test_realloc() {
+        let fail = FailAlloc::new(FailPolicy::Nth(2));
+        let mut v = Vec::new_in(&fail);
+        for i in 0..16 {
+            v.push(i);
+        }
+        assert!(v.try_push(16).is_err());
+        assert!(v.len() == 16 && v[15] == 15);
+        assert!(v.try_push(16).is_ok());
+        assert!(fail.failures() == 1);
+    }
+
+    #[test]
+    fn test_sweep_collections() {
+        let runs = fail_sweep(|a| {
+            let mut v = Vec::new_in(a);
+            for i in 0..100usize {
+                if v.try_push(i).is_err() { break }
+            }
+            for (i, x) in v.iter().enumerate() {
+                assert!(*x == i);
+            }
+
+            // the values allocate too, so failures hit them as well as the table
+            let mut hm = HashMap::new_in(a);
+            for i in 0..100usize {
+                let mut value = Vec::new_in(a);
+                if value.try_push(i).is_err() { break }
+                if hm.try_set(i, value).is_err() { break }
+            }
+            for i in 0..hm.count() {
+                let value = hm.get(i).unwrap();
+                assert!(value.len() == 1 && value[0] == i);
+            }
+
+            let mut s = String::new_in(a);
+            for _ in 0..10 {
+                if s.try_push_str("0123456789").is_err() { break }
+            }
+            assert!(s.len() % 10 == 0);
+            assert!(s.as_str().chars().all(|c| c.is_ascii_digit()));
+        });
+        assert!(runs > 10);
+    }
+}
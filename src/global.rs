@@ -0,0 +1,194 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::alloc::*;
+use crate::spin::*;
+use crate::UsableSize;
+
+///
+/// Adapter installing an allocator that is not `Sync`, like `Arena`, with
+/// `#[global_allocator]`: every call takes a spin lock around the allocator.
+/// Allocators that are already `Sync` (`System`, `StatsAlloc`, `SizeClassAlloc`,
+/// `ThreadCache`...) are installed directly. The inner allocator must not call
+/// the global allocator itself, which none of this crate's allocators do.
+///
+/// `System` as the global allocator, over-aligned blocks keep their alignment and
+/// contents when `realloc` moves them:
+///
+/// ```
+/// use rs_alloc::System;
+///
+/// #[global_allocator]
+/// static GLOBAL: System = System;
+///
+/// #[repr(align(4096))]
+/// struct Page([u8; 4096]);
+///
+/// fn main() {
+///     let mut v = Vec::new();
+///     for i in 0..300 {
+///         v.push(Page([i as u8; 4096]));
+///         assert!(v.as_ptr() as usize % 4096 == 0);
+///     }
+///     assert!(v.iter().enumerate().all(|(i, p)| p.0[0] == i as u8 && p.0[4095] == i as u8));
+/// }
+/// ```
+///
+/// A wrapper counting everything the program allocates:
+///
+/// ```
+/// use rs_alloc::StatsAlloc;
+///
+/// #[global_allocator]
+/// static GLOBAL: StatsAlloc = StatsAlloc::new();
+///
+/// fn main() {
+///     let before = GLOBAL.stats();
+///     let s: String = (0..100).map(|i| i.to_string()).collect();
+///     let d = GLOBAL.stats().diff(&before);
+///     assert!(s.len() == 190);
+///     assert!(d.allocations > 0 && d.reallocations > 0);
+/// }
+/// ```
+///
+/// An arena behind the adapter, shared by all threads and never giving memory back:
+///
+/// ```
+/// use rs_alloc::{Arena, GlobalAdapter};
+///
+/// #[global_allocator]
+/// static GLOBAL: GlobalAdapter<Arena> = GlobalAdapter::new(Arena::new());
+///
+/// fn main() {
+///     let threads: Vec<_> = (0..4).map(|t| std::thread::spawn(move || {
+///         let v: Vec<u64> = (0..1000).map(|i| i * t).collect();
+///         v.iter().sum::<u64>()
+///     })).collect();
+///     let sums: Vec<u64> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+///     assert!(sums == [0, 499500, 999000, 1498500]);
+///     assert!(GLOBAL.with(|arena| arena.chunk_count()) > 0);
+/// }
+/// ```
+///
+pub struct GlobalAdapter<A> {
+    inner   : SpinLock<A>,
+}
+
+impl<A> GlobalAdapter<A> {
+    pub const fn new(inner: A) -> Self { Self { inner: SpinLock::new(inner) } }
+
+    // run `f` on the inner allocator with the lock held, `f` must not allocate from it
+    pub fn with<R, F: FnOnce(&A) -> R>(&self, f: F) -> R { f(&self.inner.lock()) }
+}
+
+unsafe impl<A: Send> GlobalAlloc for GlobalAdapter<A> where for<'a> &'a A: GlobalAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (&*self.inner.lock()).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (&*self.inner.lock()).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (&*self.inner.lock()).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        (&*self.inner.lock()).realloc(ptr, layout, new_size)
+    }
+}
+
+unsafe impl<A: Send> GlobalAlloc for &GlobalAdapter<A> where for<'a> &'a A: GlobalAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl<A: Send> UsableSize for GlobalAdapter<A> where for<'a> &'a A: UsableSize {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { (&*self.inner.lock()).usable_size(ptr, layout) }
+}
+
+unsafe impl<A: Send> UsableSize for &GlobalAdapter<A> where for<'a> &'a A: UsableSize {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { (*self).usable_size(ptr, layout) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    // grow and shrink a block through `sizes` at every alignment up to `max_align`,
+    // checking it stays aligned and keeps its first and last bytes
+    unsafe fn realloc_sweep<A: GlobalAlloc>(a: &A, sizes: &[usize], max_align: usize) {
+        let mut align = 1;
+        while align <= max_align {
+            let mut l = Layout::from_size_align(sizes[0], align).unwrap();
+            let p = a.alloc(l);
+            assert!(!p.is_null() && (p as usize) & (align - 1) == 0);
+            *p = 0x5a;
+            *p.add(l.size() - 1) = 0xa5;
+
+            let mut p = p;
+            for &size in &sizes[1..] {
+                let q = a.realloc(p, l, size);
+                assert!(!q.is_null(), "align {} {} -> {}", align, l.size(), size);
+                assert!((q as usize) & (align - 1) == 0, "align {} {} -> {}", align, l.size(), size);
+                assert!(*q == 0x5a);
+                if size >= l.size() { assert!(*q.add(l.size() - 1) == 0xa5) }
+                *q.add(size - 1) = 0xa5;
+                p = q;
+                l = Layout::from_size_align(size, align).unwrap();
+            }
+            a.dealloc(p, l);
+            align *= 4;
+        }
+    }
+
+    const SIZES : [usize; 10] = [2, 24, 100, 5000, 70000, MMAP_THRESHOLD, MMAP_THRESHOLD + 7, 5 << 20, 64, 8];
+
+    #[test]
+    fn test_realloc_over_aligned() {
+        unsafe {
+            realloc_sweep(&System, &SIZES, 1 << 16);
+            realloc_sweep(&StatsAlloc::new(), &SIZES, 1 << 16);
+            realloc_sweep(&SizeClassAlloc::new(), &SIZES, 1 << 16);
+            realloc_sweep(&ThreadCache::new(), &SIZES, 1 << 16);
+            realloc_sweep(&BudgetAlloc::new(1 << 30), &SIZES, 1 << 16);
+            realloc_sweep(&GlobalAdapter::new(Arena::new()), &SIZES[..5], 1 << 16);
+        }
+    }
+
+    #[test]
+    fn test_adapter_collections() {
+        let global = GlobalAdapter::new(Arena::with_chunk_size(256));
+        let mut v = Vec::new_in(&global);
+        let mut s = String::new_in(&global);
+        for i in 0..1000 {
+            v.push(i);
+            s.push_str("x");
+        }
+        assert!(v[999] == 999 && s.len() == 1000);
+        assert!(global.with(|a| a.chunk_count()) > 1);
+    }
+}
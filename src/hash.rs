@@ -52,7 +52,7 @@ pub fn murmur_hash_64a (key: &[u8], seed: u64) -> u64 {
     let end = key.len() - len;
 
     while i < end {
-        let mut k = key[i + 0] as u64;
+        let mut k = key[i] as u64;
         k |= (key[i + 1] as u64) << 8;
         k |= (key[i + 2] as u64) << 16;
         k |= (key[i + 3] as u64) << 24;
@@ -29,6 +29,7 @@
 //
 
 use core::*;
+use core::alloc::GlobalAlloc;
 use crate::*;
 use crate::hash::*;
 
@@ -43,22 +44,49 @@ impl<K: Hash + PartialEq, V> KeyValue<K, V> {
     pub fn is_empty(&self) -> bool { self.hash == 0 }
 }
 
-pub struct HashMap<K: Hash + PartialEq, V> {
+pub struct HashMap<K: Hash + PartialEq, V, A: GlobalAlloc = System> {
     table   : Unique<KeyValue<K, V>>,
     capacity: usize,
     count   : usize,
+    alloc   : A,
 }
 
 impl<K: Hash + PartialEq, V> HashMap<K, V> {
     pub fn new() -> Self {
+        Self::new_in(System)
+    }
+
+    #[track_caller]
+    pub fn with_capacity(count: usize) -> Self {
+        Self::with_capacity_in(count, System)
+    }
+}
+
+#[cfg(all(unix, feature = "libc"))]
+impl<K: Hash + PartialEq, V> HashMap<K, V, HugePageAlloc> {
+    #[track_caller]
+    pub fn with_capacity_huge(count: usize) -> Self {
+        Self::with_capacity_in(count, HugePageAlloc::new())
+    }
+}
+
+impl<K: Hash + PartialEq, V, A: GlobalAlloc> HashMap<K, V, A> {
+    pub const fn new_in(alloc: A) -> Self {
         Self {
             table   : Unique::new(ptr::null_mut()),
             count   : 0,
             capacity: 0,
+            alloc,
         }
     }
 
     pub fn count(&self) -> usize { self.count }
+    pub fn capacity(&self) -> usize { self.capacity }
+
+    pub fn allocator(&self) -> &A { &self.alloc }
+
+    // start of the table block, null before the first allocation
+    pub(crate) fn table_ptr(&self) -> *const u8 { self.table.get_ptr() as *const u8 }
 
     #[inline]
     fn hash(k: &K) -> usize {
@@ -75,17 +103,26 @@ impl<K: Hash + PartialEq, V> HashMap<K, V> {
         ret
     }
 
+    fn entries(&self) -> &[KeyValue<K, V>] {
+        if self.capacity == 0 { return &[] }
+        unsafe { core::slice::from_raw_parts(self.table.get_ptr(), self.capacity) }
+    }
+
+    fn entries_mut(&mut self) -> &mut [KeyValue<K, V>] {
+        if self.capacity == 0 { return &mut [] }
+        unsafe { core::slice::from_raw_parts_mut(self.table.get_mut_ptr(), self.capacity) }
+    }
+
     fn unchecked_set(&mut self, k: K, v: V) {
         let hash    = Self::hash(&k);
         let mut index   = (hash & (self.capacity - 1)) as isize;
-        let entries = unsafe { core::slice::from_raw_parts_mut(self.table.get_mut_ptr(), self.capacity) };
+        let capacity = self.capacity;
 
-        for _ in 0..self.capacity {
-            let mut e = &mut entries[index as usize];
+        for _ in 0..capacity {
+            let e = &mut self.entries_mut()[index as usize];
             if e.is_empty() {
-                e.key   = k;
-                e.value = v;
-                e.hash  = hash;
+                // the slot holds no live key/value: write without dropping
+                unsafe { ptr::write(e, KeyValue { hash, key: k, value: v }) };
                 self.count += 1;
                 return;
             }
@@ -100,64 +137,15 @@ impl<K: Hash + PartialEq, V> HashMap<K, V> {
         panic!("uncheckedSet shouldn't reach this point");
     }
 
-    fn new_with_cap(cap: usize) -> Self {
-        Self {
-            table   : Unique::new(unsafe { alloc_array_zeroed(cap) }),
-            count   : 0,
-            capacity: cap,
-        }
-    }
-
-    fn grow(&mut self, new_cap: usize) {
-        let old_entries  = unsafe { core::slice::from_raw_parts(self.table.get_ptr(), self.capacity) };
-        let mut new_hm   = HashMap::<K, V>::new_with_cap(new_cap);
-        for o in old_entries {
-            if !o.is_empty() {
-                unsafe {
-                new_hm.unchecked_set(::core::ptr::read_unaligned(&o.key),
-                                   ::core::ptr::read_unaligned(&o.value));
-                }
-            }
-        }
-
-        unsafe { free_array_ptr(self.table.get_mut_ptr(), self.capacity) };
-        self.count  = 0;
-        self.capacity = 0;
-
-        *self = new_hm;
-    }
-
-    pub fn set(&mut self, k: K, v: V) {
-        if 4 * self.count >= 3 * self.capacity {
-            self.grow(if self.capacity == 0 { 4 } else { self.capacity * 2 });
-        }
-        self.unchecked_set(k, v)
-    }
-
     pub fn exist(&self, k: K) -> bool {
-        let hash = Self::hash(&k);
-        let mut index   = (hash & (self.capacity - 1)) as isize;
-        let entries = unsafe { core::slice::from_raw_parts(self.table.get_ptr(), self.capacity) };
-
-        for _ in 0..self.capacity {
-            let e = &entries[index as usize];
-            if e.is_empty() {
-                return false;
-            }
-
-            if hash == e.hash && k == e.key {
-                return true;
-            }
-
-            index = self.next(index);
-        }
-        false
+        self.get(k).is_some()
     }
 
     pub fn get(&self, k: K) -> Option<&V> {
+        if self.capacity == 0 { return None }
         let hash = Self::hash(&k);
         let mut index   = (hash & (self.capacity - 1)) as isize;
-        let entries = unsafe { core::slice::from_raw_parts(self.table.get_ptr(), self.capacity) };
+        let entries = self.entries();
 
         for _ in 0..self.capacity {
             let e = &entries[index as usize];
@@ -174,63 +162,161 @@ impl<K: Hash + PartialEq, V> HashMap<K, V> {
         None
     }
 
+    pub fn iter(&self) -> HashMapIter<'_, K, V> {
+        HashMapIter { entries: self.entries().iter() }
+    }
+
+    #[allow(clippy::nonminimal_bool)]
     pub fn remove(&mut self, k: K) {
+        if self.capacity == 0 { return }
         let hash = Self::hash(&k);
         let mut index   = (hash & (self.capacity - 1)) as isize;
-        let entries = unsafe { core::slice::from_raw_parts_mut(self.table.get_mut_ptr(), self.capacity) };
+        let capacity = self.capacity;
 
-        for _ in 0..self.capacity {
-            let e = &entries[index as usize];
+        let mut found = false;
+        for _ in 0..capacity {
+            let e = &self.entries()[index as usize];
             if e.is_empty() {
                 return;
             }
 
             if hash == e.hash && k == e.key {
-                self.count -= 1;
+                found = true;
                 break;
             }
 
             index = self.next(index);
         }
 
+        if !found { return }
+
+        self.count -= 1;
+        {
+            // drop the removed key/value, the slot is then treated as raw memory
+            let e = &mut self.entries_mut()[index as usize];
+            e.hash = 0;
+            unsafe { ptr::drop_in_place(&mut e.key) };
+            unsafe { ptr::drop_in_place(&mut e.value) };
+        }
+
         loop {
             let empty_index = index;
             let mut original_index;
             loop {
                 index = self.next(index);
-                let s = &entries[index as usize];
+                let s = &self.entries()[index as usize];
                 if s.is_empty() {
-                    entries[empty_index as usize].hash = 0;
-                    unsafe { ::core::ptr::read_unaligned(&entries[empty_index as usize]) };  // drop it!
                     return;
                 }
 
                 original_index   = (s.hash & (self.capacity - 1)) as isize;
 
-                if ! ((index <= original_index && original_index < empty_index)
+                if !((index <= original_index && original_index < empty_index)
                     || (original_index < empty_index && empty_index < index)
                     || (empty_index < index && index <= original_index)) {
                     break;
                 }
             }
 
-            entries[empty_index as usize] = unsafe { ::core::ptr::read_unaligned(&entries[index as usize]) };
+            let entries = self.entries_mut();
+            unsafe { ptr::copy_nonoverlapping(&entries[index as usize], &mut entries[empty_index as usize], 1) };
             entries[index as usize].hash = 0;
         }
     }
 }
 
-impl<K : Hash + PartialEq, V> Drop for HashMap<K, V> {
+impl<K: Hash + PartialEq, V, A: UsableSize> HashMap<K, V, A> {
+    // the table is sized so that `count` entries can be set without growing
+    #[track_caller]
+    pub fn with_capacity_in(count: usize, alloc: A) -> Self {
+        let mut hm = Self::new_in(alloc);
+        if count > 0 {
+            if let Err(e) = hm.try_grow((count * 4 / 3 + 1).next_power_of_two()) {
+                handle_alloc_error(e)
+            }
+        }
+        hm
+    }
+
+    // on failure the map is left untouched
+    #[track_caller]
+    fn try_grow(&mut self, min_cap: usize) -> Result<(), AllocError> {
+        let new_table    = unsafe { try_alloc_array_zeroed_in::<KeyValue<K, V>, A>(&self.alloc, min_cap)? };
+        let old_table    = self.table.get_mut_ptr();
+
+        // take the slack the allocator gave as long as the size stays a power of two,
+        // only the requested slots come zeroed
+        let usable       = unsafe { usable_count_in(&self.alloc, new_table, min_cap) };
+        let new_cap      = 1 << (usize::BITS - 1 - usable.leading_zeros());
+        unsafe { ptr::write_bytes(new_table.add(min_cap), 0, new_cap - min_cap) };
+        let old_cap      = self.capacity;
+
+        self.table      = Unique::new(new_table);
+        self.capacity   = new_cap;
+        self.count      = 0;
+
+        if old_cap == 0 { return Ok(()) }
+
+        let old_entries  = unsafe { core::slice::from_raw_parts(old_table, old_cap) };
+        for o in old_entries {
+            if !o.is_empty() {
+                unsafe {
+                self.unchecked_set(::core::ptr::read(&o.key),
+                                   ::core::ptr::read(&o.value));
+                }
+            }
+        }
+
+        unsafe { free_array_ptr_in(&self.alloc, old_table, old_cap) };
+        Ok(())
+    }
+
+    // hands the key and value back on failure, like `Vec::try_push`
+    #[track_caller]
+    pub fn try_set(&mut self, k: K, v: V) -> Result<(), ((K, V), AllocError)> {
+        if 4 * self.count >= 3 * self.capacity {
+            let new_cap = if self.capacity == 0 { Some(4) } else { self.capacity.checked_mul(2) };
+            let grown = match new_cap {
+                Some(c) => self.try_grow(c),
+                None => Err(AllocError::CapacityOverflow),
+            };
+            if let Err(e) = grown {
+                return Err(((k, v), e))
+            }
+        }
+        self.unchecked_set(k, v);
+        Ok(())
+    }
+
+    #[track_caller]
+    pub fn set(&mut self, k: K, v: V) {
+        if let Err((_, e)) = self.try_set(k, v) {
+            handle_alloc_error(e)
+        }
+    }
+}
+
+pub struct HashMapIter<'a, K: Hash + PartialEq, V> {
+    entries : core::slice::Iter<'a, KeyValue<K, V>>,
+}
+
+impl<'a, K: Hash + PartialEq, V> Iterator for HashMapIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.by_ref().find(|e| !e.is_empty()).map(|e| (&e.key, &e.value))
+    }
+}
+
+impl<K : Hash + PartialEq, V, A: GlobalAlloc> Drop for HashMap<K, V, A> {
     fn drop(&mut self) {
-            if self.capacity > 0 {
-            let arr      = unsafe { core::slice::from_raw_parts_mut(self.table.get_mut_ptr(), self.capacity) };
-            for kv in arr {
+        if self.capacity > 0 {
+            for kv in self.entries_mut() {
                 if !kv.is_empty() {
-                    unsafe { ptr::drop_in_place(&kv.key as *const K as *mut K) };
-                    unsafe { ptr::drop_in_place(&kv.value as *const V as *mut V) };
+                    unsafe { ptr::drop_in_place(&mut kv.key) };
+                    unsafe { ptr::drop_in_place(&mut kv.value) };
                 }
             }
-            unsafe { free_array_ptr(self.table.get_mut_ptr(), self.capacity) }
+            unsafe { free_array_ptr_in(&self.alloc, self.table.get_mut_ptr(), self.capacity) }
         }
     }
 }
@@ -239,6 +325,10 @@ impl Hash for i32 {
     fn hash(&self) -> usize { *self as usize }
 }
 
+impl Hash for usize {
+    fn hash(&self) -> usize { murmur_hash_64a(&self.to_ne_bytes(), 0) as usize }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -256,7 +346,7 @@ mod test {
             assert!(ret.is_some());
             match ret {
                 Some(o) => assert!(*o == i * 2),
-                None => assert!(false)
+                None => unreachable!()
             }
         }
     }
@@ -270,7 +360,7 @@ mod test {
 
         for i in 45..55 {
             hm.remove(i);
-            assert!(hm.exist(i) == false);
+            assert!(!hm.exist(i));
         }
 
         for i in 0..45 {
@@ -278,7 +368,7 @@ mod test {
             assert!(ret.is_some());
             match ret {
                 Some(o) => assert!(*o == i * 2),
-                None => assert!(false)
+                None => unreachable!()
             }
         }
 
@@ -287,17 +377,29 @@ mod test {
             assert!(ret.is_some());
             match ret {
                 Some(o) => assert!(*o == i * 2),
-                None => assert!(false)
+                None => unreachable!()
             }
         }
 
         for i in 45..55 {
-            assert!(hm.exist(i) == false);
+            assert!(!hm.exist(i));
         }
 
         assert!(hm.count() == 90);
     }
 
+    #[test]
+    fn test_new_in() {
+        let mut hm = HashMap::<i32, i32, System>::new_in(System);
+        assert!(hm.get(1).is_none());
+        hm.remove(1);
+        for i in 0..100 {
+            hm.set(i, i * 2);
+        }
+        assert!(hm.count() == 100);
+        assert!(*hm.get(42).unwrap() == 84);
+    }
+
     #[test]
     fn test_vec_insert() {
         let mut hm = HashMap::<i32, Vec<i32>>::new();
@@ -318,11 +420,10 @@ mod test {
                         assert!((*o)[j as usize] == j)
                     }
                 }
-                None => assert!(false)
+                None => unreachable!()
             }
         }
     }
-/*
     #[test]
     fn test_vec_remove() {
         let mut hm = HashMap::<i32, Vec<i32>>::new();
@@ -336,7 +437,7 @@ mod test {
 
         for i in 45..55 {
             hm.remove(i);
-            assert!(hm.exist(i) == false);
+            assert!(!hm.exist(i));
         }
 
         for i in 0..45 {
@@ -348,7 +449,7 @@ mod test {
                         assert!((*o)[j as usize] == j)
                     }
                 },
-                None => assert!(false)
+                None => unreachable!()
             }
         }
 
@@ -361,15 +462,63 @@ mod test {
                         assert!((*o)[j as usize] == j)
                     }
                 },
-                None => assert!(false)
+                None => unreachable!()
             }
         }
 
         for i in 45..55 {
-            assert!(hm.exist(i) == false);
+            assert!(!hm.exist(i));
         }
 
         assert!(hm.count() == 90);
     }
-*/
+
+    #[test]
+    fn test_try_set() {
+        let stack = StackAlloc::with_capacity(1024);
+        let mut hm = HashMap::<i32, i32, &StackAlloc>::new_in(&stack);
+        let mut failed = None;
+        for i in 0..1000 {
+            if let Err((kv, _)) = hm.try_set(i, i * 2) {
+                failed = Some((i, kv));
+                break
+            }
+        }
+        let (i, kv) = failed.unwrap();
+        assert!(kv == (i, i * 2) && !hm.exist(i));
+        for i in 0..hm.count() as i32 {
+            assert!(*hm.get(i).unwrap() == i * 2);
+        }
+    }
+
+    // hands out four times what is asked for
+    struct Roomy(Tlsf);
+
+    unsafe impl GlobalAlloc for &Roomy {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.0.alloc(Layout::from_size_align_unchecked(layout.size() * 4, layout.align()))
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { self.0.dealloc(ptr, layout) }
+    }
+
+    unsafe impl UsableSize for &Roomy {
+        unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { self.0.usable_size(ptr, layout) }
+    }
+
+    #[test]
+    fn test_usable_capacity() {
+        extern crate std;
+        let region = std::boxed::Box::leak(std::vec![0u8; 64 * 1024].into_boxed_slice());
+        let roomy = Roomy(Tlsf::with_region(region));
+        let mut hm = HashMap::new_in(&roomy);
+        hm.set(1usize, 1usize);
+        assert!(hm.capacity() == 16);
+        for i in 0..100 {
+            hm.set(i, i);
+        }
+        for i in 0..100 {
+            assert!(*hm.get(i).unwrap() == i);
+        }
+    }
 }
+
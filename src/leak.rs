@@ -0,0 +1,337 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use core::panic::Location;
+use crate::*;
+use crate::spin::*;
+
+//
+// The allocating entry points of the crate (`alloc`, `alloc_array`, `Box::new`,
+// `Vec::push`...) are `#[track_caller]`, and so are the GlobalAlloc methods of
+// `LeakAlloc`: a block allocated through a collection is recorded with the line
+// that called the collection. Calls that go through something that doesn't track
+// its caller (another wrapper, a `dyn` call) record that place instead, `alloc_at`
+// and `realloc_at` take the location explicitly.
+//
+#[derive(Clone, Copy, Debug)]
+pub struct LeakRecord {
+    pub ptr         : usize,
+    pub layout      : Layout,
+    pub location    : Option<&'static Location<'static>>,
+    pub tag         : Option<&'static str>,
+}
+
+impl fmt::Display for LeakRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}: {} bytes, align {}", self.ptr, self.layout.size(), self.layout.align())?;
+        if let Some(l) = self.location {
+            write!(f, " at {}:{}:{}", l.file(), l.line(), l.column())?;
+        }
+        if let Some(t) = self.tag {
+            write!(f, " [{}]", t)?;
+        }
+        Ok(())
+    }
+}
+
+struct Table<A: GlobalAlloc> {
+    live            : Option<HashMap<usize, LeakRecord, A>>,
+    invalid_frees   : usize,
+    untracked       : usize,
+}
+
+unsafe impl<A: GlobalAlloc + Send> Send for Table<A> {}
+
+///
+/// Debug wrapper recording every live block of the inner allocator. The side table
+/// is itself allocated from the inner allocator and never shows up in the report.
+///
+pub struct LeakAlloc<A: UsableSize + Clone = System> {
+    inner   : A,
+    table   : SpinLock<Table<A>>,
+}
+
+impl LeakAlloc {
+    pub const fn new() -> Self { Self::new_in(System) }
+}
+
+impl Default for LeakAlloc {
+    fn default() -> Self { Self::new() }
+}
+
+impl<A: UsableSize + Clone> LeakAlloc<A> {
+    pub const fn new_in(inner: A) -> Self {
+        Self {
+            inner,
+            table: SpinLock::new(Table { live: None, invalid_frees: 0, untracked: 0 }),
+        }
+    }
+
+    // handle recording the blocks allocated through it with `tag`
+    pub fn tagged(&self, tag: &'static str) -> LeakTag<'_, A> { LeakTag { leaks: self, tag } }
+
+    pub unsafe fn alloc_at(&self, layout: Layout, location: &'static Location<'static>) -> *mut u8 {
+        self.alloc_tagged(layout, Some(location), None, false)
+    }
+
+    pub unsafe fn realloc_at(&self, ptr: *mut u8, layout: Layout, new_size: usize, location: &'static Location<'static>) -> *mut u8 {
+        self.realloc_tagged(ptr, layout, new_size, Some(location), None)
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.table.lock().live.as_ref().map_or(0, |m| m.count())
+    }
+
+    pub fn outstanding_bytes(&self) -> usize {
+        self.table.lock().live.as_ref().map_or(0, |m| m.iter().map(|(_, r)| r.layout.size()).sum())
+    }
+
+    // frees of pointers that were not allocated through this allocator (or freed twice)
+    pub fn invalid_frees(&self) -> usize { self.table.lock().invalid_frees }
+
+    // blocks handed out but not recorded because the side table couldn't grow, they
+    // are missing from the report and freeing them counts as an invalid free
+    pub fn untracked(&self) -> usize { self.table.lock().untracked }
+
+    pub fn for_each_outstanding<F: FnMut(&LeakRecord)>(&self, mut f: F) {
+        if let Some(m) = self.table.lock().live.as_ref() {
+            for (_, r) in m.iter() {
+                f(r);
+            }
+        }
+    }
+
+    pub fn dump<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        let t = self.table.lock();
+        let count = t.live.as_ref().map_or(0, |m| m.count());
+        writeln!(w, "{} outstanding allocation(s)", count)?;
+        if let Some(m) = t.live.as_ref() {
+            for (_, r) in m.iter() {
+                writeln!(w, "  {}", r)?;
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn alloc_tagged(&self, layout: Layout, location: Option<&'static Location<'static>>, tag: Option<&'static str>, zeroed: bool) -> *mut u8 {
+        let p = if zeroed { self.inner.alloc_zeroed(layout) } else { self.inner.alloc(layout) };
+        if !p.is_null() { self.record(p, layout, location, tag) }
+        p
+    }
+
+    unsafe fn realloc_tagged(&self, ptr: *mut u8, layout: Layout, new_size: usize, location: Option<&'static Location<'static>>, tag: Option<&'static str>) -> *mut u8 {
+        let p = self.inner.realloc(ptr, layout, new_size);
+        if !p.is_null() {
+            let old = self.forget(ptr);
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            self.record(p, new_layout, location.or_else(|| old.and_then(|r| r.location)), tag.or_else(|| old.and_then(|r| r.tag)));
+        }
+        p
+    }
+
+    // the user's block is already allocated, failing to record it must not abort
+    fn record(&self, ptr: *mut u8, layout: Layout, location: Option<&'static Location<'static>>, tag: Option<&'static str>) {
+        let mut t = self.table.lock();
+        let inner = &self.inner;
+        let recorded = t.live.get_or_insert_with(|| HashMap::new_in(inner.clone()))
+            .try_set(ptr as usize, LeakRecord { ptr: ptr as usize, layout, location, tag });
+        if recorded.is_err() { t.untracked += 1 }
+    }
+
+    fn forget(&self, ptr: *mut u8) -> Option<LeakRecord> {
+        let mut t = self.table.lock();
+        let r = t.live.as_ref().and_then(|m| m.get(ptr as usize)).copied();
+        match r {
+            Some(_) => t.live.as_mut().unwrap().remove(ptr as usize),
+            None => t.invalid_frees += 1,
+        }
+        r
+    }
+}
+
+unsafe impl<A: UsableSize + Clone> GlobalAlloc for LeakAlloc<A> {
+    #[track_caller]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_tagged(layout, Some(Location::caller()), None, false)
+    }
+
+    #[track_caller]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc_tagged(layout, Some(Location::caller()), None, true)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.forget(ptr);
+        self.inner.dealloc(ptr, layout)
+    }
+
+    #[track_caller]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.realloc_tagged(ptr, layout, new_size, Some(Location::caller()), None)
+    }
+}
+
+unsafe impl<A: UsableSize + Clone> GlobalAlloc for &LeakAlloc<A> {
+    #[track_caller]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    #[track_caller]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    #[track_caller]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+// allocates from a LeakAlloc and tags what it allocates, see `LeakAlloc::tagged`
+#[derive(Clone, Copy)]
+pub struct LeakTag<'a, A: UsableSize + Clone = System> {
+    leaks   : &'a LeakAlloc<A>,
+    tag     : &'static str,
+}
+
+unsafe impl<A: UsableSize + Clone> GlobalAlloc for LeakTag<'_, A> {
+    #[track_caller]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.leaks.alloc_tagged(layout, Some(Location::caller()), Some(self.tag), false)
+    }
+
+    #[track_caller]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.leaks.alloc_tagged(layout, Some(Location::caller()), Some(self.tag), true)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { self.leaks.dealloc(ptr, layout) }
+
+    #[track_caller]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.leaks.realloc_tagged(ptr, layout, new_size, Some(Location::caller()), Some(self.tag))
+    }
+}
+
+unsafe impl<A: UsableSize + Clone> UsableSize for LeakAlloc<A> {}
+unsafe impl<A: UsableSize + Clone> UsableSize for &LeakAlloc<A> {}
+unsafe impl<A: UsableSize + Clone> UsableSize for LeakTag<'_, A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_leaks() {
+        let leaks = LeakAlloc::new();
+        {
+            let mut hm = HashMap::new_in(&leaks);
+            for i in 0..100 {
+                let mut v = Vec::new_in(&leaks);
+                v.push(i);
+                hm.set(i, v);
+            }
+            for i in 0..50 {
+                hm.remove(i);
+            }
+            let b = Box::new_in(String::from_in("boxed", &leaks), &leaks);
+            let _s = b.unbox();
+        }
+        assert!(leaks.outstanding() == 0);
+        assert!(leaks.invalid_frees() == 0);
+    }
+
+    #[test]
+    fn test_report() {
+        let leaks = LeakAlloc::new();
+        let line = line!() + 1;
+        let b = Box::new_in(1234u64, &leaks);
+        let tagged = Box::new_in(1u32, leaks.tagged("frame"));
+        Box::into_raw(b);
+        Box::into_raw(tagged);
+
+        assert!(leaks.outstanding() == 2);
+        assert!(leaks.outstanding_bytes() == 12);
+
+        let mut report = String::new();
+        leaks.dump(&mut report).unwrap();
+        let expected = format!("src/leak.rs:{}", line);
+        assert!(report.as_str().contains(expected.as_str()));
+        assert!(report.as_str().contains("[frame]"));
+
+        let mut sizes = 0;
+        leaks.for_each_outstanding(|r| sizes += r.layout.size());
+        assert!(sizes == 12);
+    }
+
+    #[test]
+    fn test_location_not_shared() {
+        let leaks = LeakAlloc::new();
+        // a System allocation right before must not lend its location to the direct call
+        let mut v = Vec::new();
+        v.push(1);
+        let line = line!() + 1;
+        let p = unsafe { leaks.alloc(Layout::new::<u64>()) };
+        let here = Location::caller();
+        let q = unsafe { leaks.alloc_at(Layout::new::<u32>(), here) };
+
+        let mut lines = Vec::new();
+        leaks.for_each_outstanding(|r| lines.push(r.location.unwrap().line()));
+        assert!(lines.as_slice().contains(&line) && lines.as_slice().contains(&here.line()));
+        unsafe {
+            leaks.dealloc(p, Layout::new::<u64>());
+            leaks.dealloc(q, Layout::new::<u32>());
+        }
+    }
+
+    #[test]
+    fn test_invalid_free() {
+        let leaks = LeakAlloc::new();
+        let a = &leaks;
+        let l = Layout::new::<u64>();
+        unsafe {
+            let p = sysalloc.alloc(l);
+            a.dealloc(p, l);
+        }
+        assert!(leaks.invalid_frees() == 1);
+    }
+
+    #[test]
+    fn test_table_out_of_memory() {
+        // the block itself is the first allocation, the side table the second
+        let fail = FailAlloc::new(FailPolicy::Nth(2));
+        let leaks = LeakAlloc::new_in(&fail);
+        let a = &leaks;
+        let l = Layout::new::<u64>();
+        unsafe {
+            let p = a.alloc(l);
+            assert!(!p.is_null());
+            assert!(leaks.untracked() == 1 && leaks.outstanding() == 0);
+            a.dealloc(p, l);
+        }
+    }
+}
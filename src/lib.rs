@@ -29,26 +29,66 @@
 //
 #![no_std]
 #![allow(dead_code, non_snake_case, non_camel_case_types, non_upper_case_globals)]
-#[allow(improper_ctypes)]
+#![allow(clippy::missing_safety_doc, clippy::new_without_default, clippy::len_without_is_empty)]
 
-use core::alloc::*;
+use core::alloc::{GlobalAlloc, Layout};
 use core::*;
 use core::sync::atomic::*;
 
 mod os;
-use os::*;
+mod spin;
+pub use os::*;
+
+// the C allocation API, a cdylib needs std for its panic handler. Linux only,
+// errno is reached through glibc/musl's `__errno_location`
+#[cfg(all(feature = "malloc", target_os = "linux"))]
+extern crate std;
+#[cfg(all(feature = "malloc", target_os = "linux"))]
+pub mod cabi;
 
 pub mod hash;
 pub mod vec;
 pub mod hashmap;
 pub mod string;
+pub mod arena;
+pub mod pool;
+pub mod stack;
+pub mod tlsf;
+pub mod buddy;
+pub mod stats;
+pub mod leak;
+pub mod canary;
+pub mod tcache;
+pub mod sizeclass;
+pub mod profile;
+pub mod trace;
+pub mod budget;
+pub mod fail;
+#[cfg(feature = "global")]
+pub mod global;
 
 pub use vec::*;
 pub use hashmap::*;
 pub use string::*;
-
-pub unsafe fn realloc_fallback(
-    alloc: &System,
+pub use arena::*;
+pub use pool::*;
+pub use stack::*;
+pub use tlsf::*;
+pub use buddy::*;
+pub use stats::*;
+pub use leak::*;
+pub use canary::*;
+pub use tcache::*;
+pub use sizeclass::*;
+pub use profile::*;
+pub use trace::*;
+pub use budget::*;
+pub use fail::*;
+#[cfg(feature = "global")]
+pub use global::*;
+
+pub unsafe fn realloc_fallback<A: GlobalAlloc>(
+    alloc: &A,
     ptr: *mut u8,
     old_layout: Layout,
     new_size: usize,
@@ -67,57 +107,189 @@ pub unsafe fn realloc_fallback(
 
 pub const sysalloc : System = System;
 
-pub unsafe fn alloc<T>() -> *mut T {
-     sysalloc.alloc(Layout::new::<T>()) as *mut rs_ctypes::c_void as *mut T
+//
+// Allocators often hand out more than asked for (size classes, malloc rounding, whole
+// pages). Implementing `usable_size` promises the extra bytes can be used, and that
+// `dealloc` and `realloc` accept the block described with any size between the
+// requested one and the usable one. The default reports the requested size, which is
+// always correct. The collections grow through this trait, so an allocator that only
+// implements `GlobalAlloc` needs an empty `unsafe impl UsableSize for MyAlloc {}`.
+//
+pub unsafe trait UsableSize: GlobalAlloc {
+    unsafe fn usable_size(&self, _ptr: *mut u8, layout: Layout) -> usize { layout.size() }
+}
+
+// number of `T` that fit in the array of `count` elements just allocated at `ptr`
+pub unsafe fn usable_count_in<T, A: UsableSize>(alloc: &A, ptr: *mut T, count: usize) -> usize {
+    let size = mem::size_of::<T>();
+    if size == 0 { return count }
+    let layout = Layout::from_size_align_unchecked(size * count, mem::align_of::<T>());
+    cmp::max(count, alloc.usable_size(ptr as *mut u8, layout) / size)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocError {
+    CapacityOverflow,           // the requested size can't be described by a Layout
+    OutOfMemory(Layout),        // the allocator returned null for this layout
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::CapacityOverflow => write!(f, "capacity overflow"),
+            AllocError::OutOfMemory(l) => write!(f, "out of memory allocating {} bytes (align {})", l.size(), l.align()),
+        }
+    }
+}
+
+pub type OomHandler = fn(Layout) -> !;
+
+static OOM_HANDLER : AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+fn default_oom_handler(layout: Layout) -> ! {
+    os::abort_with(format_args!("memory allocation of {} bytes (align {}) failed\n", layout.size(), layout.align()))
+}
+
+// install the handler called when an infallible allocation fails, returns the previous one
+pub fn set_oom_handler(handler: OomHandler) -> OomHandler {
+    let prev = OOM_HANDLER.swap(handler as *mut (), Ordering::SeqCst);
+    if prev.is_null() { default_oom_handler } else { unsafe { mem::transmute::<*mut (), OomHandler>(prev) } }
+}
+
+pub fn reset_oom_handler() {
+    OOM_HANDLER.store(ptr::null_mut(), Ordering::SeqCst);
+}
+
+#[cold]
+pub fn oom(layout: Layout) -> ! {
+    let h = OOM_HANDLER.load(Ordering::SeqCst);
+    if h.is_null() {
+        default_oom_handler(layout)
+    } else {
+        unsafe { mem::transmute::<*mut (), OomHandler>(h)(layout) }
+    }
+}
+
+// infallible paths end up here when the fallible one they are built on fails
+#[cold]
+#[track_caller]
+pub(crate) fn handle_alloc_error(e: AllocError) -> ! {
+    match e {
+        AllocError::CapacityOverflow => panic!("capacity overflow"),
+        AllocError::OutOfMemory(layout) => oom(layout),
+    }
+}
+
+#[inline]
+fn null_to_oom<T>(p: *mut u8, layout: Layout) -> *mut T {
+    if p.is_null() { oom(layout) }
+    p as *mut T
+}
+
+#[inline]
+fn check_null<T>(p: *mut u8, layout: Layout) -> Result<*mut T, AllocError> {
+    if p.is_null() { Err(AllocError::OutOfMemory(layout)) } else { Ok(p as *mut T) }
+}
+
+#[track_caller]
+pub unsafe fn try_alloc_in<T, A: GlobalAlloc>(a: &A) -> Result<*mut T, AllocError> {
+    let layout = Layout::new::<T>();
+    check_null(a.alloc(layout), layout)
+}
+
+#[track_caller]
+pub unsafe fn try_alloc_array_in<T, A: GlobalAlloc>(a: &A, res_count: usize) -> Result<*mut T, AllocError> {
+    let layout = Layout::array::<T>(res_count).map_err(|_| AllocError::CapacityOverflow)?;
+    check_null(a.alloc(layout), layout)
+}
+
+#[track_caller]
+pub unsafe fn try_alloc_array_zeroed_in<T, A: GlobalAlloc>(a: &A, res_count: usize) -> Result<*mut T, AllocError> {
+    let layout = Layout::array::<T>(res_count).map_err(|_| AllocError::CapacityOverflow)?;
+    check_null(a.alloc_zeroed(layout), layout)
+}
+
+// on failure the old block is left untouched
+#[track_caller]
+pub unsafe fn try_realloc_array_in<T, A: GlobalAlloc>(a: &A, ptr: *mut T, count: usize, new_count: usize) -> Result<*mut T, AllocError> {
+    let layout = Layout::array::<T>(count).map_err(|_| AllocError::CapacityOverflow)?;
+    let new_layout = Layout::array::<T>(new_count).map_err(|_| AllocError::CapacityOverflow)?;
+    check_null(a.realloc(ptr as *mut u8, layout, new_layout.size()), new_layout)
+}
+
+#[track_caller]
+pub unsafe fn try_alloc<T>() -> Result<*mut T, AllocError> { try_alloc_in(&sysalloc) }
+#[track_caller]
+pub unsafe fn try_alloc_array<T>(res_count: usize) -> Result<*mut T, AllocError> { try_alloc_array_in(&sysalloc, res_count) }
+
+#[track_caller]
+pub unsafe fn alloc_in<T, A: GlobalAlloc>(a: &A) -> *mut T {
+    let layout = Layout::new::<T>();
+    null_to_oom(a.alloc(layout), layout)
 }
 
-pub unsafe fn free<T>(t: *mut T) {
-    sysalloc.dealloc(t as *mut u8, Layout::new::<T>());
+pub unsafe fn free_in<T, A: GlobalAlloc>(a: &A, t: *mut T) {
+    a.dealloc(t as *mut u8, Layout::new::<T>());
 }
 
 // TODO: change this to const generics when they become stable and return a slice
-pub unsafe fn alloc_array<T>(res_count: usize) -> *mut T {
+#[track_caller]
+pub unsafe fn alloc_array_in<T, A: GlobalAlloc>(a: &A, res_count: usize) -> *mut T {
     let l = Layout::array::<T>(res_count);
     match l {
-        Ok(layout) => sysalloc.alloc(layout) as *mut T,
+        Ok(layout) => null_to_oom(a.alloc(layout), layout),
         _ => panic!("unable to create layout")
     }
 }
 
-pub unsafe fn alloc_array_zeroed<T>(res_count: usize) -> *mut T {
+#[track_caller]
+pub unsafe fn alloc_array_zeroed_in<T, A: GlobalAlloc>(a: &A, res_count: usize) -> *mut T {
     let l = Layout::array::<T>(res_count);
     match l {
-        Ok(layout) => sysalloc.alloc_zeroed(layout) as *mut T,
+        Ok(layout) => null_to_oom(a.alloc_zeroed(layout), layout),
+        _ => panic!("unable to create layout")
+    }
+}
+
+// moves the elements over to a block of `new_count` elements, the allocator can do it in place
+#[track_caller]
+pub unsafe fn realloc_array_in<T, A: GlobalAlloc>(a: &A, ptr: *mut T, count: usize, new_count: usize) -> *mut T {
+    match (Layout::array::<T>(count), Layout::array::<T>(new_count)) {
+        (Ok(layout), Ok(new_layout)) => null_to_oom(a.realloc(ptr as *mut u8, layout, new_layout.size()), new_layout),
         _ => panic!("unable to create layout")
     }
 }
 
 // TODO: change this to slice once const generics stable
-pub unsafe fn free_array<T>(ptr: *mut T, count: usize, res_count: usize) {
+pub unsafe fn free_array_in<T, A: GlobalAlloc>(a: &A, ptr: *mut T, count: usize, res_count: usize) {
     if count > res_count {
         panic!("count exceeded reserved count")
     }
 
     let arr      = core::slice::from_raw_parts_mut(ptr, count); // this will keep a pointer (will not free it)
-    for i in 0..count {
-        ::core::ptr::drop_in_place(&arr[i] as *const T as *mut T);
-    }
-    let l = Layout::array::<T>(count);
-    match l {
-        Ok(layout) => sysalloc.dealloc(ptr as *mut u8, layout),
-        _ => panic!("unable to create layout")
-    }
+    ::core::ptr::drop_in_place(arr);
+    free_array_ptr_in(a, ptr, res_count)
 }
 
 // TODO: change this to slice once const generics stable
-pub unsafe fn free_array_ptr<T>(ptr: *mut T, count: usize) {
+pub unsafe fn free_array_ptr_in<T, A: GlobalAlloc>(a: &A, ptr: *mut T, count: usize) {
     let l = Layout::array::<T>(count);
     match l {
-        Ok(layout) => sysalloc.dealloc(ptr as *mut u8, layout),
+        Ok(layout) => a.dealloc(ptr as *mut u8, layout),
         _ => panic!("unable to create layout")
     }
 }
 
+#[track_caller]
+pub unsafe fn alloc<T>() -> *mut T { alloc_in(&sysalloc) }
+pub unsafe fn free<T>(t: *mut T) { free_in(&sysalloc, t) }
+#[track_caller]
+pub unsafe fn alloc_array<T>(res_count: usize) -> *mut T { alloc_array_in(&sysalloc, res_count) }
+#[track_caller]
+pub unsafe fn alloc_array_zeroed<T>(res_count: usize) -> *mut T { alloc_array_zeroed_in(&sysalloc, res_count) }
+pub unsafe fn free_array<T>(ptr: *mut T, count: usize, res_count: usize) { free_array_in(&sysalloc, ptr, count, res_count) }
+pub unsafe fn free_array_ptr<T>(ptr: *mut T, count: usize) { free_array_ptr_in(&sysalloc, ptr, count) }
+
 
 ////////////////////////////////////////////////////////////////////////////////
 /// TODO: remove these when the alloc handler stabilize in alloc
@@ -129,41 +301,65 @@ pub struct Unique<T: ?Sized> {
 }
 
 impl<T: ?Sized> Unique<T> {
-    pub fn new(ptr: *mut T) -> Self { Self { ptr : ptr, _marker: ::core::marker::PhantomData } }
+    pub const fn new(ptr: *mut T) -> Self { Self { ptr, _marker: ::core::marker::PhantomData } }
     pub fn get_mut_ptr(&mut self) -> *mut T { self.ptr }
     pub fn get_ptr(&self) -> *const T { self.ptr }
 }
 
 #[repr(C)]
-pub struct Box<T: ?Sized>{
-    uptr: Unique<T>
+pub struct Box<T: ?Sized, A: GlobalAlloc = System> {
+    uptr: Unique<T>,
+    alloc: A,
 }
 
-impl<T: ?Sized> Drop for Box<T> {
+impl<T: ?Sized, A: GlobalAlloc> Drop for Box<T, A> {
     fn drop(&mut self) {
         unsafe {
+            let layout = Layout::for_value(&*self.uptr.get_ptr());
             ::core::ptr::drop_in_place(self.uptr.get_mut_ptr());
             let addr = self.uptr.get_mut_ptr() as *mut u8;  // TODO: this is a hack to pass thin to fat type conversion error
-            free(addr);
+            self.alloc.dealloc(addr, layout);
         }
     }
 }
 
 impl<T: Sized> Box<T> {
     #[inline(always)]
+    #[track_caller]
     pub fn new(x: T) -> Self {
+        Self::new_in(x, System)
+    }
+
+    #[track_caller]
+    pub fn try_new(x: T) -> Result<Self, AllocError> {
+        Self::try_new_in(x, System)
+    }
+}
+
+impl<T: Sized, A: GlobalAlloc> Box<T, A> {
+    #[inline(always)]
+    #[track_caller]
+    pub fn new_in(x: T, alloc: A) -> Self {
+        match Self::try_new_in(x, alloc) {
+            Ok(b) => b,
+            Err(e) => handle_alloc_error(e)
+        }
+    }
+
+    #[track_caller]
+    pub fn try_new_in(x: T, alloc: A) -> Result<Self, AllocError> {
         unsafe {
-            let addr = alloc::<T>();
+            let addr = try_alloc_in::<T, A>(&alloc)?;
             ptr::write(addr, x);
-            Self { uptr: Unique::new(addr) }
+            Ok(Self { uptr: Unique::new(addr), alloc })
         }
     }
 
     pub fn unbox(self) -> T {
         unsafe {
-            let ptr = self.uptr.ptr;
-            let v = Self::into_raw(self).read();
-            free(ptr);
+            let (ptr, alloc) = Self::into_raw_with_allocator(self);
+            let v = ptr.read();
+            free_in(&alloc, ptr);
             v
         }
     }
@@ -173,18 +369,30 @@ impl<T: Sized> Box<T> {
     }
 }
 
-
 impl<T: ?Sized> Box<T> {
+    pub fn from_raw(raw: *mut T) -> Self {
+        Self::from_raw_in(raw, System)
+    }
+}
+
+#[allow(clippy::should_implement_trait)]
+impl<T: ?Sized, A: GlobalAlloc> Box<T, A> {
     pub fn as_ref(&self) -> &T { unsafe { &(*self.uptr.get_ptr()) } }
-    pub fn as_mut(&mut self) -> &T { unsafe { &mut (*self.uptr.get_mut_ptr()) } }
+    pub fn as_mut(&mut self) -> &mut T { unsafe { &mut (*self.uptr.get_mut_ptr()) } }
     pub fn into_raw(this: Self) -> *mut T {
+        Self::into_raw_with_allocator(this).0
+    }
+
+    pub fn into_raw_with_allocator(this: Self) -> (*mut T, A) {
         let m = ::core::mem::ManuallyDrop::new(this);
-        m.uptr.ptr
+        (m.uptr.ptr, unsafe { ptr::read(&m.alloc) })
     }
 
-    pub fn from_raw(raw: *mut T) -> Self {
-        Self { uptr: Unique::new(raw) }
+    pub fn from_raw_in(raw: *mut T, alloc: A) -> Self {
+        Self { uptr: Unique::new(raw), alloc }
     }
+
+    pub fn allocator(this: &Self) -> &A { &this.alloc }
 }
 
 
@@ -206,65 +414,77 @@ impl<T: ?Sized> ArcCell<T> {
     pub fn dec(&mut self) -> isize { self.count.fetch_sub(1, Ordering::SeqCst) }
 }
 
-pub struct Arc<T: ?Sized>(*mut ArcCell<T>);
+pub struct Arc<T: ?Sized, A: GlobalAlloc = System>(*mut ArcCell<T>, A);
 
-impl<T: ?Sized> Arc<T> {
-    pub fn as_ptr(this: &Arc<T>) -> *const T {
+impl<T: ?Sized, A: GlobalAlloc> Arc<T, A> {
+    pub fn as_ptr(this: &Self) -> *const T {
         unsafe { &(*this.0).data as *const T }
     }
+
+    pub fn allocator(this: &Self) -> &A { &this.1 }
 }
 
 impl<T: Sized> Arc<T> {
+    #[track_caller]
     pub fn new(x: T) -> Self {
+        Self::new_in(x, System)
+    }
+}
+
+impl<T: Sized, A: GlobalAlloc> Arc<T, A> {
+    #[track_caller]
+    pub fn new_in(x: T, alloc: A) -> Self {
         unsafe {
-            let addr = alloc::<ArcCell<T>>();
+            let addr = alloc_in::<ArcCell<T>, A>(&alloc);
             ptr::write(addr, ArcCell { data: x, count: AtomicIsize::new(1) });
-            Self(addr)
+            Self(addr, alloc)
         }
     }
 }
 
-impl<T: ?Sized> Drop for Arc<T> {
+impl<T: ?Sized, A: GlobalAlloc> Drop for Arc<T, A> {
     fn drop(&mut self) {
         unsafe {
             let s = &mut (*self.0);
             if s.dec() == 1 {
+                let layout = Layout::for_value(&*self.0);
                 ::core::ptr::drop_in_place(self.0);
                 let addr = self.0 as *mut u8;  // TODO: this is a hack to pass thin to fat type conversion error
-                free(addr);
+                self.1.dealloc(addr, layout);
             }
         }
     }
 }
 
-impl<T: ?Sized> Clone for Arc<T> {
+impl<T: ?Sized, A: GlobalAlloc + Clone> Clone for Arc<T, A> {
     fn clone(&self) -> Self {
         unsafe {
             let s = &mut (*self.0);
             s.inc();
-            Self(self.0)
+            Self(self.0, self.1.clone())
         }
     }
 }
 
 
-impl<T: ?Sized> core::ops::Deref for Arc<T> {
+impl<T: ?Sized, A: GlobalAlloc> core::ops::Deref for Arc<T, A> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
         unsafe { &(*self.0).data }
     }
 }
 
-impl<T: ?Sized> AsRef<T> for Arc<T> {
+impl<T: ?Sized, A: GlobalAlloc> AsRef<T> for Arc<T, A> {
     fn as_ref(&self) -> &T {
         unsafe { &(*self.0).data }
     }
 }
 
-unsafe impl<T: ?Sized> Send for Arc<T> {}
-unsafe impl<T: ?Sized> Sync for Arc<T> {}
+unsafe impl<T: ?Sized, A: GlobalAlloc + Send + Sync> Send for Arc<T, A> {}
+unsafe impl<T: ?Sized, A: GlobalAlloc + Send + Sync> Sync for Arc<T, A> {}
 
 #[cfg(test)]
+#[allow(drop_bounds, clippy::vec_init_then_push)]
 mod tests {
     use super::*;
     extern crate std;
@@ -376,4 +596,86 @@ mod tests {
         assert_eq!(f.a[0], 123);
         assert_eq!(f.a[1], 456);
     }
+
+    #[test]
+    fn testNewIn() {
+        let b = Box::new_in(1234, System);
+        assert_eq!(*b.as_ref(), 1234);
+        assert_eq!(b.unbox(), 1234);
+
+        let a = Arc::new_in(TestStruct { a: std::vec![1, 2] }, System);
+        let a2 = a.clone();
+        assert_eq!(a2.a[1], 2);
+    }
+
+    #[test]
+    fn testTryNew() {
+        let stack = StackAlloc::with_capacity(16);
+        let b = Box::try_new_in(1u64, &stack);
+        assert!(b.is_ok());
+        assert!(Box::try_new_in([0u64; 4], &stack).is_err());
+        assert!(*Box::try_new(1234).unwrap().as_ref() == 1234);
+        assert!(unsafe { try_alloc_array::<u64>(usize::MAX) } == Err(AllocError::CapacityOverflow));
+    }
+
+    fn panicking_oom(layout: Layout) -> ! {
+        panic!("oom handler called for {} bytes", layout.size())
+    }
+
+    #[test]
+    #[should_panic(expected = "oom handler called for 32 bytes")]
+    fn testOomHandler() {
+        // put the previous handler back once the test panics
+        struct Restore(OomHandler);
+        impl Drop for Restore {
+            fn drop(&mut self) { set_oom_handler(self.0); }
+        }
+
+        let _restore = Restore(set_oom_handler(panicking_oom));
+        let stack = StackAlloc::with_capacity(16);
+        let _b = Box::new_in([0u64; 4], &stack);
+    }
+
+    #[test]
+    fn testDefaultOomAborts() {
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                reset_oom_handler();
+                let stack = StackAlloc::with_capacity(16);
+                let mut v = Vec::new_in(&stack);
+                for i in 0..100u64 {
+                    v.push(i);
+                }
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            libc::waitpid(pid, &mut status, 0);
+            assert!(libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGABRT);
+        }
+    }
+
+    // an allocator that only implements GlobalAlloc, as written before UsableSize existed
+    struct Plain;
+
+    unsafe impl GlobalAlloc for Plain {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 { System.alloc(layout) }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { System.dealloc(ptr, layout) }
+    }
+
+    unsafe impl UsableSize for Plain {}
+
+    #[test]
+    fn testCustomAllocator() {
+        let mut v = Vec::with_capacity_in(3, Plain);
+        assert!(v.capacity() == 3);
+        for i in 0..100 {
+            v.push(i);
+        }
+        assert!(v.len() == 100 && v[99] == 99);
+
+        let mut hm = HashMap::new_in(Plain);
+        hm.set(1, 2);
+        assert!(*hm.get(1).unwrap() == 2);
+    }
 }
@@ -0,0 +1,242 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+//
+// libc free System: small blocks come from a TLSF heap grown with regions mapped
+// through raw syscalls, large blocks are mapped directly and grow with mremap
+//
+use core::alloc::*;
+use core::*;
+use crate::*;
+use super::syscall;
+
+#[derive(Clone, Copy, Default)]
+pub struct System;
+
+pub const MMAP_THRESHOLD : usize = 1 << 20;
+
+// the heap grows by at least this much at a time
+pub const HEAP_REGION_SIZE : usize = 4 << 20;
+
+const PAGE_SIZE : usize = 4096;
+
+static HEAP : Tlsf = Tlsf::new();
+
+#[inline]
+fn is_mapped(size: usize, align: usize) -> bool { size >= MMAP_THRESHOLD && align <= PAGE_SIZE }
+
+#[inline]
+pub(crate) unsafe fn map_pages(size: usize) -> *mut u8 { syscall::mmap(size) }
+
+#[inline]
+pub(crate) unsafe fn unmap_pages(ptr: *mut u8, size: usize) { syscall::munmap(ptr, size) }
+
+// map a region big enough for `layout` (with room for the heap's own headers) and hand it to the heap
+unsafe fn grow_heap(layout: &Layout) -> bool {
+    let needed = match Tlsf::region_size(layout) {
+        Some(n) => n,
+        None => return false
+    };
+    let size = cmp::max(HEAP_REGION_SIZE, (needed + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+    let p = map_pages(size);
+    if p.is_null() { return false }
+    HEAP.add_region(slice::from_raw_parts_mut(p, size));
+    true
+}
+
+unsafe impl GlobalAlloc for System {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_mapped(layout.size(), layout.align()) {
+            return map_pages(layout.size())
+        }
+
+        loop {
+            let p = HEAP.alloc(layout);
+            if !p.is_null() || !grow_heap(&layout) { return p }
+        }
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // fresh mappings are already zeroed
+        if is_mapped(layout.size(), layout.align()) {
+            return map_pages(layout.size())
+        }
+
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_mapped(layout.size(), layout.align()) {
+            unmap_pages(ptr, layout.size())
+        } else {
+            HEAP.dealloc(ptr, layout)
+        }
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_mapped = is_mapped(layout.size(), layout.align());
+        let new_mapped = is_mapped(new_size, layout.align());
+        if old_mapped && new_mapped {
+            syscall::mremap(ptr, layout.size(), new_size)
+        } else if old_mapped || new_mapped {
+            realloc_fallback(self, ptr, layout, new_size)
+        } else {
+            // the heap may be full, in which case the fallback grows it
+            let p = HEAP.realloc(ptr, layout, new_size);
+            if !p.is_null() { p } else { realloc_fallback(self, ptr, layout, new_size) }
+        }
+    }
+}
+
+unsafe impl UsableSize for System {
+    #[inline]
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize {
+        if is_mapped(layout.size(), layout.align()) {
+            (layout.size() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+        } else if layout.align() <= PAGE_SIZE {
+            // a heap block reported at the threshold would be unmapped
+            cmp::min(HEAP.usable_size(ptr, layout), MMAP_THRESHOLD - 1)
+        } else {
+            HEAP.usable_size(ptr, layout)
+        }
+    }
+}
+
+// write the message to stderr and abort, without allocating
+pub(crate) fn abort_with(args: fmt::Arguments<'_>) -> ! {
+    struct Stderr;
+    impl fmt::Write for Stderr {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            unsafe { syscall::write(2, s.as_bytes()) };
+            Ok(())
+        }
+    }
+
+    let _ = fmt::Write::write_fmt(&mut Stderr, args);
+    syscall::abort()
+}
+
+pub(crate) fn now_nanos() -> u64 {
+    let (sec, nsec) = syscall::clock_monotonic();
+    sec as u64 * 1_000_000_000 + nsec as u64
+}
+
+// the heap hands out 16 byte aligned blocks on every target
+pub const MIN_ALIGN: usize = 16;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_blocks() {
+        let mut ptrs = [ptr::null_mut(); 64];
+        unsafe {
+            for (i, p) in ptrs.iter_mut().enumerate() {
+                let l = Layout::from_size_align(8 + i * 24, 8).unwrap();
+                *p = System.alloc(l);
+                assert!(!p.is_null() && (*p as usize) & (MIN_ALIGN - 1) == 0);
+                ptr::write_bytes(*p, i as u8, l.size());
+            }
+            for (i, p) in ptrs.iter().enumerate() {
+                let l = Layout::from_size_align(8 + i * 24, 8).unwrap();
+                assert!(*p.add(l.size() - 1) == i as u8);
+                System.dealloc(*p, l);
+            }
+        }
+    }
+
+    #[test]
+    fn test_heap_grows() {
+        // more than one heap region worth of blocks just under the mmap threshold
+        let l = Layout::from_size_align(MMAP_THRESHOLD - 64, 64).unwrap();
+        let mut ptrs = [ptr::null_mut(); 8];
+        unsafe {
+            for p in ptrs.iter_mut() {
+                *p = System.alloc(l);
+                assert!(!p.is_null() && (*p as usize) & 63 == 0);
+                **p = 7;
+            }
+            for p in ptrs.iter() {
+                assert!(**p == 7);
+                System.dealloc(*p, l);
+            }
+        }
+    }
+
+    #[test]
+    fn test_large_over_aligned() {
+        // too aligned to be mapped directly, the heap has to grow a region that fits it
+        let l = Layout::from_size_align(4 << 20, 8192).unwrap();
+        unsafe {
+            let p = System.alloc(l);
+            assert!(!p.is_null() && (p as usize) & 8191 == 0);
+            *p.add(l.size() - 1) = 1;
+            System.dealloc(p, l);
+        }
+    }
+
+    #[test]
+    fn test_large_blocks() {
+        let l = Layout::from_size_align(MMAP_THRESHOLD, 8).unwrap();
+        unsafe {
+            let p = System.alloc_zeroed(l);
+            assert!(!p.is_null());
+            assert!(*p.add(MMAP_THRESHOLD - 1) == 0);
+            *p = 1;
+
+            let q = System.realloc(p, l, 8 * MMAP_THRESHOLD);
+            assert!(*q == 1);
+            let r = System.realloc(q, Layout::from_size_align(8 * MMAP_THRESHOLD, 8).unwrap(), 16);
+            assert!(*r == 1);
+            System.dealloc(r, Layout::from_size_align(16, 8).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_collections() {
+        let mut v = crate::Vec::new();
+        let mut hm = crate::HashMap::new();
+        for i in 0..10000usize {
+            v.push(i);
+            hm.set(i, i * 2);
+        }
+        assert!(v[9999] == 9999 && hm.get(9999) == Some(&19998));
+    }
+}
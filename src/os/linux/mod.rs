@@ -0,0 +1,3 @@
+pub mod syscall;
+pub mod alloc;
+pub use alloc::*;
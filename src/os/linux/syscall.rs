@@ -0,0 +1,126 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+//
+// raw linux system calls, enough for the allocator and the abort path without libc
+//
+use core::arch::asm;
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        pub const SYS_WRITE         : usize = 1;
+        pub const SYS_MMAP          : usize = 9;
+        pub const SYS_MUNMAP        : usize = 11;
+        pub const SYS_MREMAP        : usize = 25;
+        pub const SYS_GETPID        : usize = 39;
+        pub const SYS_KILL          : usize = 62;
+        pub const SYS_EXIT_GROUP    : usize = 231;
+        pub const SYS_CLOCK_GETTIME : usize = 228;
+
+        #[inline]
+        pub unsafe fn syscall6(n: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize, a6: usize) -> isize {
+            let ret: isize;
+            asm!("syscall",
+                 inlateout("rax") n as isize => ret,
+                 in("rdi") a1, in("rsi") a2, in("rdx") a3, in("r10") a4, in("r8") a5, in("r9") a6,
+                 lateout("rcx") _, lateout("r11") _,
+                 options(nostack));
+            ret
+        }
+    } else if #[cfg(target_arch = "aarch64")] {
+        pub const SYS_WRITE         : usize = 64;
+        pub const SYS_MMAP          : usize = 222;
+        pub const SYS_MUNMAP        : usize = 215;
+        pub const SYS_MREMAP        : usize = 216;
+        pub const SYS_GETPID        : usize = 172;
+        pub const SYS_KILL          : usize = 129;
+        pub const SYS_EXIT_GROUP    : usize = 94;
+        pub const SYS_CLOCK_GETTIME : usize = 113;
+
+        #[inline]
+        pub unsafe fn syscall6(n: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize, a6: usize) -> isize {
+            let ret: isize;
+            asm!("svc 0",
+                 in("x8") n,
+                 inlateout("x0") a1 as isize => ret,
+                 in("x1") a2, in("x2") a3, in("x3") a4, in("x4") a5, in("x5") a6,
+                 options(nostack));
+            ret
+        }
+    } else {
+        compile_error!("the raw-syscalls feature supports x86_64 and aarch64 only");
+    }
+}
+
+pub const PROT_READ         : usize = 1;
+pub const PROT_WRITE        : usize = 2;
+pub const MAP_PRIVATE       : usize = 0x02;
+pub const MAP_ANONYMOUS     : usize = 0x20;
+pub const MREMAP_MAYMOVE    : usize = 1;
+pub const SIGABRT           : usize = 6;
+pub const CLOCK_MONOTONIC   : usize = 1;
+
+// the kernel returns -errno in [-4095, -1] on failure
+#[inline]
+fn is_error(ret: isize) -> bool { (-4095..0).contains(&ret) }
+
+pub unsafe fn mmap(size: usize) -> *mut u8 {
+    let ret = syscall6(SYS_MMAP, 0, size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, usize::MAX, 0);
+    if is_error(ret) { core::ptr::null_mut() } else { ret as *mut u8 }
+}
+
+pub unsafe fn munmap(ptr: *mut u8, size: usize) {
+    syscall6(SYS_MUNMAP, ptr as usize, size, 0, 0, 0, 0);
+}
+
+pub unsafe fn mremap(ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+    let ret = syscall6(SYS_MREMAP, ptr as usize, old_size, new_size, MREMAP_MAYMOVE, 0, 0);
+    if is_error(ret) { core::ptr::null_mut() } else { ret as *mut u8 }
+}
+
+pub unsafe fn write(fd: usize, buf: &[u8]) -> isize {
+    syscall6(SYS_WRITE, fd, buf.as_ptr() as usize, buf.len(), 0, 0, 0)
+}
+
+pub fn clock_monotonic() -> (i64, i64) {
+    let mut ts = [0i64; 2];
+    unsafe { syscall6(SYS_CLOCK_GETTIME, CLOCK_MONOTONIC, ts.as_mut_ptr() as usize, 0, 0, 0, 0) };
+    (ts[0], ts[1])
+}
+
+// raise SIGABRT like abort(3) would, exiting with the matching status if it is caught
+pub fn abort() -> ! {
+    unsafe {
+        let pid = syscall6(SYS_GETPID, 0, 0, 0, 0, 0, 0);
+        syscall6(SYS_KILL, pid as usize, SIGABRT, 0, 0, 0, 0);
+        loop { syscall6(SYS_EXIT_GROUP, 128 + SIGABRT, 0, 0, 0, 0, 0); }
+    }
+}
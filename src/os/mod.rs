@@ -1,6 +1,28 @@
+#[cfg(all(unix, feature = "libc"))]
+pub mod unix;
+#[cfg(all(unix, feature = "libc"))]
+pub use unix::{guard::*, huge::*, secure::*};
+
+// the platform layer provides pages, a clock, the abort path and the default System
 cfg_if::cfg_if! {
-    if #[cfg(unix)] {
-        pub mod unix;
-        pub use unix::*;
+    if #[cfg(all(target_os = "linux", feature = "raw-syscalls"))] {
+        pub mod linux;
+        use linux as platform;
+    } else if #[cfg(all(unix, feature = "libc"))] {
+        use unix as platform;
+    } else {
+        compile_error!("this target needs either the `libc` or the `raw-syscalls` feature");
     }
-}
\ No newline at end of file
+}
+
+pub use platform::{MIN_ALIGN, MMAP_THRESHOLD};
+pub(crate) use platform::{map_pages, unmap_pages, abort_with, now_nanos};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "buddy")] {
+        pub mod buddy;
+        pub use buddy::*;
+    } else {
+        pub use platform::System;
+    }
+}
@@ -2,14 +2,59 @@ use core::alloc::*;
 use core::*;
 use crate::*;
 
+#[derive(Clone, Copy, Default)]
 pub struct System;
 
+// blocks of at least this size are mapped directly from the OS instead of going
+// through malloc, and on linux grow with mremap without copying
+pub const MMAP_THRESHOLD : usize = 1 << 20;
+
+// mmap only guarantees page alignment, 4096 being the smallest page size around
+const MMAP_MAX_ALIGN : usize = 4096;
+
+#[inline]
+fn is_mapped(size: usize, align: usize) -> bool { size >= MMAP_THRESHOLD && align <= MMAP_MAX_ALIGN }
+
+#[inline]
+pub(crate) unsafe fn map_pages(size: usize) -> *mut u8 {
+    let p = libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+    if p == libc::MAP_FAILED { ptr::null_mut() } else { p as *mut u8 }
+}
+
+#[inline]
+pub(crate) unsafe fn unmap_pages(ptr: *mut u8, size: usize) {
+    libc::munmap(ptr as *mut libc::c_void, size);
+}
+
+#[cfg(target_os = "linux")]
+#[inline]
+unsafe fn remap(ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+    let p = libc::mremap(ptr as *mut libc::c_void, old_size, new_size, libc::MREMAP_MAYMOVE);
+    if p == libc::MAP_FAILED { ptr::null_mut() } else { p as *mut u8 }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+unsafe fn remap(ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+    let p = map_pages(new_size);
+    if !p.is_null() {
+        ptr::copy_nonoverlapping(ptr, p, cmp::min(old_size, new_size));
+        unmap_pages(ptr, old_size);
+    }
+    p
+}
+
 //
 // from https://github.com/rust-lang/rust/blob/master/src/libstd/sys/unix/alloc.rs
 //
 unsafe impl GlobalAlloc for System {
     #[inline]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_mapped(layout.size(), layout.align()) {
+            return map_pages(layout.size())
+        }
+
         // jemalloc provides alignment less than MIN_ALIGN for small allocations.
         // So only rely on MIN_ALIGN if size >= align.
         // Also see <https://github.com/rust-lang/rust/issues/45955> and
@@ -29,6 +74,11 @@ unsafe impl GlobalAlloc for System {
 
     #[inline]
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // fresh mappings are already zeroed
+        if is_mapped(layout.size(), layout.align()) {
+            return map_pages(layout.size())
+        }
+
         // See the comment above in `alloc` for why this check looks the way it does.
         if layout.align() <= MIN_ALIGN && layout.align() <= layout.size() {
             libc::calloc(layout.size(), 1) as *mut u8
@@ -42,13 +92,23 @@ unsafe impl GlobalAlloc for System {
     }
 
     #[inline]
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        libc::free(ptr as *mut libc::c_void)
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_mapped(layout.size(), layout.align()) {
+            unmap_pages(ptr, layout.size())
+        } else {
+            libc::free(ptr as *mut libc::c_void)
+        }
     }
 
     #[inline]
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        if layout.align() <= MIN_ALIGN && layout.align() <= new_size {
+        let old_mapped = is_mapped(layout.size(), layout.align());
+        let new_mapped = is_mapped(new_size, layout.align());
+        if old_mapped && new_mapped {
+            remap(ptr, layout.size(), new_size)
+        } else if old_mapped || new_mapped {
+            realloc_fallback(self, ptr, layout, new_size)
+        } else if layout.align() <= MIN_ALIGN && layout.align() <= new_size {
             libc::realloc(ptr as *mut libc::c_void, new_size) as *mut u8
         } else {
             realloc_fallback(self, ptr, layout, new_size)
@@ -56,6 +116,50 @@ unsafe impl GlobalAlloc for System {
     }
 }
 
+unsafe impl UsableSize for System {
+    #[inline]
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize {
+        if is_mapped(layout.size(), layout.align()) {
+            (layout.size() + MMAP_MAX_ALIGN - 1) & !(MMAP_MAX_ALIGN - 1)
+        } else if layout.align() <= MMAP_MAX_ALIGN {
+            // a malloc block reported at the threshold would be unmapped
+            cmp::min(malloc_usable_size(ptr, layout), MMAP_THRESHOLD - 1)
+        } else {
+            malloc_usable_size(ptr, layout)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[inline]
+unsafe fn malloc_usable_size(ptr: *mut u8, layout: Layout) -> usize {
+    cmp::max(libc::malloc_usable_size(ptr as *mut libc::c_void), layout.size())
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+unsafe fn malloc_usable_size(_ptr: *mut u8, layout: Layout) -> usize { layout.size() }
+
+// write the message to stderr and abort, without allocating
+pub(crate) fn abort_with(args: fmt::Arguments<'_>) -> ! {
+    struct Stderr;
+    impl fmt::Write for Stderr {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            unsafe { libc::write(2, s.as_ptr() as *const libc::c_void, s.len()) };
+            Ok(())
+        }
+    }
+
+    let _ = fmt::Write::write_fmt(&mut Stderr, args);
+    unsafe { libc::abort() }
+}
+
+pub(crate) fn now_nanos() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
 #[cfg(any(
     target_os = "android",
     target_os = "illumos",
@@ -102,23 +206,56 @@ unsafe fn aligned_malloc(layout: &Layout) -> *mut u8 {
 
 // The minimum alignment guaranteed by the architecture. This value is used to
 // add fast paths for low alignment values.
-#[cfg(all(any(
+#[cfg(any(
     target_arch = "x86",
     target_arch = "arm",
     target_arch = "mips",
     target_arch = "powerpc",
     target_arch = "powerpc64",
-    target_arch = "asmjs",
     target_arch = "wasm32",
     target_arch = "hexagon"
-)))]
+))]
 pub const MIN_ALIGN: usize = 8;
-#[cfg(all(any(
+#[cfg(any(
     target_arch = "x86_64",
     target_arch = "aarch64",
     target_arch = "mips64",
     target_arch = "s390x",
     target_arch = "sparc64",
     target_arch = "riscv64"
-)))]
-pub const MIN_ALIGN: usize = 16;
\ No newline at end of file
+))]
+pub const MIN_ALIGN: usize = 16;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_blocks() {
+        let l = Layout::from_size_align(MMAP_THRESHOLD, 8).unwrap();
+        unsafe {
+            let p = System.alloc_zeroed(l);
+            assert!(!p.is_null());
+            assert!(*p.add(MMAP_THRESHOLD - 1) == 0);
+            *p = 1;
+            *p.add(MMAP_THRESHOLD - 1) = 2;
+
+            // grows through mremap, then back under the threshold through malloc
+            let q = System.realloc(p, l, 8 * MMAP_THRESHOLD);
+            assert!(*q == 1 && *q.add(MMAP_THRESHOLD - 1) == 2);
+            *q.add(8 * MMAP_THRESHOLD - 1) = 3;
+            let r = System.realloc(q, Layout::from_size_align(8 * MMAP_THRESHOLD, 8).unwrap(), 16);
+            assert!(*r == 1);
+            System.dealloc(r, Layout::from_size_align(16, 8).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_large_vec() {
+        let mut v = crate::Vec::<u8>::new();
+        for i in 0..4 * MMAP_THRESHOLD {
+            v.push(i as u8);
+        }
+        assert!(v[4 * MMAP_THRESHOLD - 1] == 255);
+    }
+}
@@ -0,0 +1,160 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::alloc::*;
+use core::*;
+use crate::UsableSize;
+
+//
+// Debug allocator: every block gets its own mapping and ends right before a
+// PROT_NONE guard page, so overflows fault on the first byte past the end. Freed
+// blocks are protected instead of being unmapped and are never reused, so any use
+// after free faults too. Address space is never given back: testing only.
+//
+#[derive(Clone, Copy, Default)]
+pub struct GuardAlloc;
+
+#[inline]
+fn page_size() -> usize { unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize } }
+
+#[inline]
+fn align_up(x: usize, align: usize) -> usize { (x + align - 1) & !(align - 1) }
+
+impl GuardAlloc {
+    // the mapping kept for a block: from the page holding its first byte to the end of the guard
+    fn mapping(ptr: *mut u8, layout: &Layout) -> (usize, usize) {
+        let page    = page_size();
+        let start   = ptr as usize & !(page - 1);
+        let guard   = align_up(ptr as usize + layout.size(), page);
+        (start, guard + page - start)
+    }
+}
+
+unsafe impl GlobalAlloc for GuardAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let page = page_size();
+        let len = match layout.size().checked_add(layout.align() + 2 * page) {
+            Some(l) => align_up(l, page),
+            None => return ptr::null_mut()
+        };
+
+        let base = libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                              libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+        if base == libc::MAP_FAILED { return ptr::null_mut() }
+        let base = base as usize;
+
+        // the block ends as close as its alignment allows to a page boundary
+        let guard   = align_up(base + layout.size() + layout.align(), page);
+        let p       = (guard - layout.size()) & !(layout.align() - 1);
+        let (start, size) = Self::mapping(p as *mut u8, &layout);
+
+        if start > base { libc::munmap(base as *mut libc::c_void, start - base); }
+        if base + len > start + size { libc::munmap((start + size) as *mut libc::c_void, base + len - start - size); }
+        libc::mprotect((start + size - page) as *mut libc::c_void, page, libc::PROT_NONE);
+        p as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (start, size) = Self::mapping(ptr, &layout);
+        // give the memory back but keep the address range reserved and faulting
+        libc::madvise(start as *mut libc::c_void, size, libc::MADV_DONTNEED);
+        libc::mprotect(start as *mut libc::c_void, size, libc::PROT_NONE);
+    }
+}
+
+unsafe impl GlobalAlloc for &GuardAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+}
+
+// the block has to end right at the guard page
+unsafe impl UsableSize for GuardAlloc {}
+unsafe impl UsableSize for &GuardAlloc {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    // run `f` in a forked child and return the signal that killed it, if any
+    fn child_signal<F: FnOnce()>(f: F) -> Option<i32> {
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                f();
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            libc::waitpid(pid, &mut status, 0);
+            if libc::WIFSIGNALED(status) { Some(libc::WTERMSIG(status)) } else { None }
+        }
+    }
+
+    #[test]
+    fn test_collections() {
+        let mut v = Vec::new_in(GuardAlloc);
+        for i in 0..10000 {
+            v.push(i);
+        }
+        let mut s = String::new_in(GuardAlloc);
+        s.push_str("guarded");
+        assert!(v[9999] == 9999);
+        assert!(s == "guarded");
+    }
+
+    #[test]
+    fn test_alignment() {
+        for i in 0..16 {
+            let align = 1 << i;
+            let l = Layout::from_size_align(100, align).unwrap();
+            let p = unsafe { GuardAlloc.alloc(l) };
+            assert!((p as usize) & (align - 1) == 0);
+            unsafe { ptr::write_bytes(p, 0xff, 100) };
+            unsafe { GuardAlloc.dealloc(p, l) };
+        }
+    }
+
+    #[test]
+    fn test_overflow_faults() {
+        let l = Layout::from_size_align(64, 1).unwrap();
+        let p = unsafe { GuardAlloc.alloc(l) };
+        assert!(child_signal(|| unsafe { ptr::write_volatile(p.add(63), 1) }).is_none());
+        assert!(child_signal(|| unsafe { ptr::write_volatile(p.add(64), 1) }) == Some(libc::SIGSEGV));
+    }
+
+    #[test]
+    fn test_use_after_free_faults() {
+        let l = Layout::new::<u64>();
+        let p = unsafe { GuardAlloc.alloc(l) };
+        unsafe { GuardAlloc.dealloc(p, l) };
+        assert!(child_signal(|| unsafe { ptr::write_volatile(p, 1) }) == Some(libc::SIGSEGV));
+    }
+}
@@ -0,0 +1,274 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use core::sync::atomic::*;
+use crate::os::{System, map_pages, unmap_pages};
+use crate::UsableSize;
+
+//
+// Wrapper for big tables: blocks of at least HUGE_PAGE_SIZE get their own mapping,
+// aligned to 2 MiB and marked with MADV_HUGEPAGE so the kernel backs them with
+// transparent huge pages. When THP is off (or the kernel doesn't have it) they are
+// plain page mappings. Smaller blocks go to the inner allocator.
+//
+pub const HUGE_PAGE_SIZE : usize = 2 << 20;
+
+// mappings only guarantee page alignment when THP is off
+const HUGE_MAX_ALIGN : usize = 4096;
+
+#[derive(Clone, Copy, Default)]
+pub struct HugePageAlloc<A: GlobalAlloc = System> {
+    inner   : A,
+}
+
+const THP_UNKNOWN   : u8 = 0;
+const THP_ON        : u8 = 1;
+const THP_OFF       : u8 = 2;
+
+static THP : AtomicU8 = AtomicU8::new(THP_UNKNOWN);
+
+#[cfg(target_os = "linux")]
+fn thp_enabled() -> bool {
+    match THP.load(Ordering::Relaxed) {
+        THP_ON  => true,
+        THP_OFF => false,
+        _ => {
+            let on = unsafe { read_thp_mode() };
+            THP.store(if on { THP_ON } else { THP_OFF }, Ordering::Relaxed);
+            on
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thp_enabled() -> bool { false }
+
+// the file reads like "always [madvise] never", with the current mode in brackets
+#[cfg(target_os = "linux")]
+unsafe fn read_thp_mode() -> bool {
+    let fd = libc::open(b"/sys/kernel/mm/transparent_hugepage/enabled\0".as_ptr() as *const libc::c_char, libc::O_RDONLY);
+    if fd < 0 { return false }
+    let mut buf = [0u8; 64];
+    let n = libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+    libc::close(fd);
+    n > 0 && !buf[..n as usize].windows(7).any(|w| w == b"[never]")
+}
+
+#[inline]
+fn is_huge(size: usize, align: usize) -> bool { size >= HUGE_PAGE_SIZE && align <= HUGE_MAX_ALIGN }
+
+#[inline]
+fn huge_size(size: usize) -> usize { (size + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1) }
+
+// `size` is a multiple of HUGE_PAGE_SIZE
+unsafe fn map_huge(size: usize) -> *mut u8 {
+    if !thp_enabled() { return map_pages(size) }
+
+    // over-map and trim both ends down to an aligned range
+    let p = map_pages(size + HUGE_PAGE_SIZE);
+    if p.is_null() { return map_pages(size) }
+    let head = huge_size(p as usize) - p as usize;
+    if head > 0 { unmap_pages(p, head) }
+    if head < HUGE_PAGE_SIZE { unmap_pages(p.add(head + size), HUGE_PAGE_SIZE - head) }
+
+    let start = p.add(head);
+    #[cfg(target_os = "linux")]
+    if libc::madvise(start as *mut libc::c_void, size, libc::MADV_HUGEPAGE) != 0 {
+        // no THP in this kernel, stop aligning
+        THP.store(THP_OFF, Ordering::Relaxed);
+    }
+    start
+}
+
+impl HugePageAlloc {
+    pub const fn new() -> Self { Self::new_in(System) }
+
+    // whether large blocks are actually backed by huge pages
+    pub fn available() -> bool { thp_enabled() }
+}
+
+impl<A: GlobalAlloc> HugePageAlloc<A> {
+    pub const fn new_in(inner: A) -> Self { Self { inner } }
+
+    pub fn inner(&self) -> &A { &self.inner }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for HugePageAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_huge(layout.size(), layout.align()) {
+            map_huge(huge_size(layout.size()))
+        } else {
+            self.inner.alloc(layout)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // fresh mappings are already zeroed
+        if is_huge(layout.size(), layout.align()) {
+            map_huge(huge_size(layout.size()))
+        } else {
+            self.inner.alloc_zeroed(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_huge(layout.size(), layout.align()) {
+            unmap_pages(ptr, huge_size(layout.size()))
+        } else {
+            self.inner.dealloc(ptr, layout)
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_huge = is_huge(layout.size(), layout.align());
+        let new_huge = is_huge(new_size, layout.align());
+        if old_huge && new_huge {
+            // shrink in place, growing moves to keep the new range aligned
+            let (old_len, new_len) = (huge_size(layout.size()), huge_size(new_size));
+            if new_len <= old_len {
+                if new_len < old_len { unmap_pages(ptr.add(new_len), old_len - new_len) }
+                return ptr
+            }
+            crate::realloc_fallback(self, ptr, layout, new_size)
+        } else if old_huge || new_huge {
+            crate::realloc_fallback(self, ptr, layout, new_size)
+        } else {
+            self.inner.realloc(ptr, layout, new_size)
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for &HugePageAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl<A: UsableSize> UsableSize for HugePageAlloc<A> {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize {
+        if is_huge(layout.size(), layout.align()) {
+            huge_size(layout.size())
+        } else if layout.align() <= HUGE_MAX_ALIGN {
+            // an inner block reported at the threshold would be unmapped
+            cmp::min(self.inner.usable_size(ptr, layout), HUGE_PAGE_SIZE - 1)
+        } else {
+            self.inner.usable_size(ptr, layout)
+        }
+    }
+}
+
+unsafe impl<A: UsableSize> UsableSize for &HugePageAlloc<A> {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { (*self).usable_size(ptr, layout) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    extern crate std;
+
+    // VmFlags of the mapping holding `addr`
+    #[cfg(target_os = "linux")]
+    fn vm_flags(addr: usize) -> std::string::String {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut inside = false;
+        for line in smaps.lines() {
+            let head = line.split(' ').next().unwrap();
+            if let Some((lo, hi)) = head.split_once('-') {
+                if let (Ok(lo), Ok(hi)) = (usize::from_str_radix(lo, 16), usize::from_str_radix(hi, 16)) {
+                    inside = lo <= addr && addr < hi;
+                    continue
+                }
+            }
+            if inside && line.starts_with("VmFlags:") {
+                return line.into()
+            }
+        }
+        panic!("mapping not found");
+    }
+
+    #[test]
+    fn test_huge_vec() {
+        let mut v = Vec::<u64, _>::with_capacity_huge(HUGE_PAGE_SIZE / 8 + 1);
+        for i in 0..HUGE_PAGE_SIZE / 4 {
+            v.push(i as u64);
+        }
+        assert!(v[HUGE_PAGE_SIZE / 4 - 1] == (HUGE_PAGE_SIZE / 4 - 1) as u64);
+        // the block takes whole huge pages
+        assert!(v.capacity() * 8 == 2 * HUGE_PAGE_SIZE);
+
+        if HugePageAlloc::available() {
+            let p = v.as_slice().as_ptr() as usize;
+            assert!(p % HUGE_PAGE_SIZE == 0);
+            #[cfg(target_os = "linux")]
+            assert!(vm_flags(p).split_whitespace().any(|f| f == "hg"));
+        }
+    }
+
+    #[test]
+    fn test_huge_hashmap() {
+        let mut hm = HashMap::with_capacity_huge(100000);
+        for i in 0..100000 {
+            hm.set(i, i * 2);
+        }
+        assert!(*hm.get(99999).unwrap() == 199998);
+
+        if HugePageAlloc::available() {
+            let p = hm.table_ptr() as usize;
+            assert!(p % HUGE_PAGE_SIZE == 0);
+            #[cfg(target_os = "linux")]
+            assert!(vm_flags(p).split_whitespace().any(|f| f == "hg"));
+        }
+    }
+
+    #[test]
+    fn test_realloc() {
+        let a = HugePageAlloc::new();
+        let l = Layout::from_size_align(3 * HUGE_PAGE_SIZE, 8).unwrap();
+        unsafe {
+            let p = a.alloc(l);
+            *p = 1;
+            *p.add(3 * HUGE_PAGE_SIZE - 1) = 2;
+
+            // shrinks in place, then moves back to the inner allocator
+            let q = a.realloc(p, l, HUGE_PAGE_SIZE + 1);
+            assert!(q == p && *q == 1);
+            let r = a.realloc(q, Layout::from_size_align(HUGE_PAGE_SIZE + 1, 8).unwrap(), 16);
+            assert!(*r == 1);
+            let s = a.realloc(r, Layout::from_size_align(16, 8).unwrap(), 4 * HUGE_PAGE_SIZE);
+            assert!(*s == 1);
+            a.dealloc(s, Layout::from_size_align(4 * HUGE_PAGE_SIZE, 8).unwrap());
+        }
+    }
+}
@@ -1,2 +1,8 @@
 pub mod alloc;
-pub use alloc::*;
\ No newline at end of file
+pub mod guard;
+pub mod huge;
+pub mod secure;
+pub use alloc::*;
+pub use guard::*;
+pub use huge::*;
+pub use secure::*;
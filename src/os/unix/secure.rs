@@ -0,0 +1,205 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use core::sync::atomic::*;
+use crate::*;
+
+//
+// Allocator for key material: blocks come from a TLSF heap over regions that are
+// locked in RAM (never swapped) and, on linux, left out of core dumps. Every block
+// is wiped when it is freed and when realloc moves it, so growing a `SecureVec` or a
+// `SecureString` leaves no copy behind. Regions are never given back. If the region
+// can't be locked (RLIMIT_MEMLOCK) the allocation fails instead of using unlocked
+// memory.
+//
+pub const SECURE_REGION_SIZE : usize = 64 * 1024;
+
+static HEAP : Tlsf = Tlsf::new();
+static LOCKED : AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Default)]
+pub struct SecureAlloc;
+
+pub type SecureVec<T> = Vec<T, SecureAlloc>;
+pub type SecureString = String<SecureAlloc>;
+
+impl SecureAlloc {
+    // locked memory mapped so far
+    pub fn locked_bytes(&self) -> usize { LOCKED.load(Ordering::Relaxed) }
+}
+
+#[inline]
+fn page_size() -> usize { unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize } }
+
+unsafe fn map_locked(size: usize) -> *mut u8 {
+    let p = libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+    if p == libc::MAP_FAILED { return ptr::null_mut() }
+    if libc::mlock(p, size) != 0 {
+        libc::munmap(p, size);
+        return ptr::null_mut()
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    libc::madvise(p, size, libc::MADV_DONTDUMP);
+    LOCKED.fetch_add(size, Ordering::Relaxed);
+    p as *mut u8
+}
+
+// map a region big enough for `layout` (with room for the heap's own headers) and hand it to the heap
+unsafe fn grow_heap(layout: &Layout) -> bool {
+    let page = page_size();
+    let needed = match Tlsf::region_size(layout) {
+        Some(n) => n,
+        None => return false
+    };
+    let size = cmp::max(SECURE_REGION_SIZE, (needed + page - 1) & !(page - 1));
+    let p = map_locked(size);
+    if p.is_null() { return false }
+    HEAP.add_region(slice::from_raw_parts_mut(p, size));
+    true
+}
+
+// volatile so the writes can't be dropped because the memory is freed right after
+#[inline]
+unsafe fn zeroize(p: *mut u8, len: usize) {
+    for i in 0..len {
+        ptr::write_volatile(p.add(i), 0);
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+unsafe impl GlobalAlloc for SecureAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let p = HEAP.alloc(layout);
+            if !p.is_null() || !grow_heap(&layout) { return p }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        zeroize(ptr, HEAP.usable_size(ptr, layout));
+        HEAP.dealloc(ptr, layout)
+    }
+
+    // always moves, so the old block goes through dealloc and gets wiped
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc_fallback(self, ptr, layout, new_size)
+    }
+}
+
+unsafe impl UsableSize for SecureAlloc {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { HEAP.usable_size(ptr, layout) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+
+    const SECRET : &str = "correct horse battery staple";
+
+    fn contains(hay: &[u8], needle: &[u8]) -> bool {
+        hay.windows(needle.len()).any(|w| w == needle)
+    }
+
+    // between them the tests lock a few regions, more than the 64 KiB RLIMIT_MEMLOCK
+    // many containers default to, skip them there instead of failing
+    fn can_lock() -> bool {
+        let mut lim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut lim) } != 0 { return false }
+        let ok = lim.rlim_cur == libc::RLIM_INFINITY || lim.rlim_cur >= 1 << 20;
+        if !ok { std::eprintln!("skipped: RLIMIT_MEMLOCK is {} bytes", lim.rlim_cur) }
+        ok
+    }
+
+    #[test]
+    fn test_wiped_on_move() {
+        if !can_lock() { return }
+        let mut s = SecureString::new_in(SecureAlloc);
+        s.push_str(SECRET);
+        let (old, old_cap) = (s.as_bytes().as_ptr(), s.as_mut_vec().capacity());
+
+        // grow until the string moves
+        while s.as_bytes().as_ptr() == old {
+            s.push_str(SECRET);
+        }
+        let stale = unsafe { slice::from_raw_parts(old, old_cap) };
+        assert!(!contains(stale, SECRET.as_bytes()));
+        assert!(contains(s.as_bytes(), SECRET.as_bytes()));
+
+        let p = s.as_bytes().as_ptr();
+        let len = s.len();
+        drop(s);
+        let freed = unsafe { slice::from_raw_parts(p, len) };
+        assert!(!contains(freed, SECRET.as_bytes()));
+    }
+
+    #[test]
+    fn test_region_sized_for_request() {
+        if !can_lock() { return }
+        // a block bigger than a region must get one region that fits, not a stream of small ones
+        let before = SecureAlloc.locked_bytes();
+        let mut v = SecureVec::<u8>::with_capacity_in(70000, SecureAlloc);
+        v.push(1);
+        assert!(SecureAlloc.locked_bytes() - before < 4 * SECURE_REGION_SIZE);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_locked_and_not_dumped() {
+        if !can_lock() { return }
+        let mut v = SecureVec::<u8>::new_in(SecureAlloc);
+        v.push(1);
+        assert!(SecureAlloc.locked_bytes() >= SECURE_REGION_SIZE);
+
+        // find the mapping holding the vector and check its flags
+        let addr = v.as_slice().as_ptr() as usize;
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut inside = false;
+        for line in smaps.lines() {
+            let head = line.split(' ').next().unwrap();
+            if let Some((lo, hi)) = head.split_once('-') {
+                if let (Ok(lo), Ok(hi)) = (usize::from_str_radix(lo, 16), usize::from_str_radix(hi, 16)) {
+                    inside = lo <= addr && addr < hi;
+                    continue
+                }
+            }
+            if inside && line.starts_with("VmFlags:") {
+                let flags: std::vec::Vec<&str> = line.split_whitespace().collect();
+                assert!(flags.contains(&"lo") && flags.contains(&"dd"), "{}", line);
+                return
+            }
+        }
+        panic!("mapping not found");
+    }
+}
@@ -0,0 +1,210 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::cell::Cell;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use crate::*;
+
+pub const POOL_BLOCK_SIZE : usize = 1024;
+
+// a free slot holds the link to the next free slot, a used one holds the value
+union Slot<T> {
+    next    : *mut Slot<T>,
+    value   : ManuallyDrop<T>,
+}
+
+///
+/// Fixed-size slab allocator: `T` slots are carved out of blocks of `block_size`
+/// slots and recycled through an intrusive free list.
+///
+pub struct Pool<T> {
+    block_size  : usize,
+    blocks      : Cell<Vec<*mut Slot<T>>>,
+    free        : Cell<*mut Slot<T>>,
+    live        : Cell<usize>,
+}
+
+impl<T> Pool<T> {
+    pub fn new() -> Self { Self::with_block_size(POOL_BLOCK_SIZE) }
+
+    pub fn with_block_size(block_size: usize) -> Self {
+        assert!(block_size > 0, "block size must be greater than zero");
+        Self {
+            block_size,
+            blocks  : Cell::new(Vec::new()),
+            free    : Cell::new(ptr::null_mut()),
+            live    : Cell::new(0),
+        }
+    }
+
+    pub fn alloc(&self, t: T) -> PoolBox<'_, T> {
+        if self.free.get().is_null() {
+            self.grow();
+        }
+
+        let slot = self.free.get();
+        unsafe {
+            self.free.set((*slot).next);
+            ptr::write(slot, Slot { value: ManuallyDrop::new(t) });
+        }
+        self.live.set(self.live.get() + 1);
+        PoolBox { slot, pool: self }
+    }
+
+    // number of live objects
+    pub fn len(&self) -> usize { self.live.get() }
+
+    // number of slots in all the blocks
+    pub fn capacity(&self) -> usize { self.block_count() * self.block_size }
+
+    pub fn block_count(&self) -> usize {
+        let blocks = self.blocks.replace(Vec::new());
+        let count = blocks.len();
+        self.blocks.set(blocks);
+        count
+    }
+
+    fn grow(&self) {
+        let block = unsafe { alloc_array::<Slot<T>>(self.block_size) };
+
+        // thread the new slots in front of the free list
+        for i in 0..self.block_size {
+            let next = if i + 1 < self.block_size { unsafe { block.add(i + 1) } } else { self.free.get() };
+            unsafe { ptr::write(block.add(i), Slot { next }) };
+        }
+        self.free.set(block);
+
+        let mut blocks = self.blocks.replace(Vec::new());
+        blocks.push(block);
+        self.blocks.set(blocks);
+    }
+
+    unsafe fn release(&self, slot: *mut Slot<T>) {
+        (*slot).next = self.free.get();
+        self.free.set(slot);
+        self.live.set(self.live.get() - 1);
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    fn drop(&mut self) {
+        for b in self.blocks.get_mut().iter() {
+            unsafe { free_array_ptr(*b, self.block_size) };
+        }
+    }
+}
+
+pub struct PoolBox<'a, T> {
+    slot    : *mut Slot<T>,
+    pool    : &'a Pool<T>,
+}
+
+impl<T> PoolBox<'_, T> {
+    pub fn into_inner(self) -> T {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            let v = ManuallyDrop::into_inner(ptr::read(&(*this.slot).value));
+            this.pool.release(this.slot);
+            v
+        }
+    }
+}
+
+impl<T> Deref for PoolBox<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T { unsafe { &(*self.slot).value } }
+}
+
+impl<T> DerefMut for PoolBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut (*self.slot).value } }
+}
+
+impl<T> Drop for PoolBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut (*self.slot).value);
+            self.pool.release(self.slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_free() {
+        let pool = Pool::<u64>::with_block_size(16);
+        let mut v = Vec::new();
+        for i in 0..100 {
+            v.push(pool.alloc(i));
+        }
+        assert!(pool.len() == 100);
+        assert!(pool.block_count() == 7);
+        for i in 0..100 {
+            assert!(*v[i] == i as u64);
+        }
+
+        drop(v);
+        assert!(pool.len() == 0);
+
+        // slots get recycled without new blocks
+        let mut v = Vec::new();
+        for i in 0..100 {
+            v.push(pool.alloc(i));
+        }
+        assert!(pool.block_count() == 7);
+    }
+
+    #[test]
+    fn test_drop_into_inner() {
+        let pool = Pool::<Vec<i32>>::new();
+        let mut b = pool.alloc(Vec::new());
+        for i in 0..100 {
+            b.push(i);
+        }
+        let c = pool.alloc(Vec::new());
+        drop(c);
+        let v = b.into_inner();
+        assert!(v.len() == 100);
+        assert!(pool.len() == 0);
+    }
+
+    #[test]
+    fn test_small_type() {
+        let pool = Pool::<u8>::with_block_size(4);
+        let a = pool.alloc(1);
+        let b = pool.alloc(2);
+        assert!(*a + *b == 3);
+    }
+}
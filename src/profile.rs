@@ -0,0 +1,310 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use core::sync::atomic::*;
+use crate::*;
+use crate::spin::*;
+
+//
+// Sizes and lifetimes are bucketed by powers of two: bucket 0 holds 0 and 1, bucket
+// i > 0 holds (2^(i-1), 2^i]. Lifetimes are measured in ticks of the allocation
+// counter, a block freed right after being allocated (no allocation in between)
+// lives for 1 tick.
+//
+pub const PROFILE_BUCKETS : usize = usize::BITS as usize + 1;
+
+// initial value of every histogram bucket
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO : AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+fn bucket(x: usize) -> usize {
+    if x <= 1 { 0 } else { (usize::BITS - (x - 1).leading_zeros()) as usize }
+}
+
+// inclusive range of values falling into a bucket
+pub fn bucket_range(i: usize) -> (usize, usize) {
+    match i {
+        0 => (0, 1),
+        _ if i == PROFILE_BUCKETS - 1 => ((1 << (i - 1)) + 1, usize::MAX),
+        _ => ((1 << (i - 1)) + 1, 1 << i),
+    }
+}
+
+struct Births<A: GlobalAlloc> {
+    live    : Option<HashMap<usize, usize, A>>,
+}
+
+unsafe impl<A: GlobalAlloc + Send> Send for Births<A> {}
+
+///
+/// Profiling wrapper: every request is counted by size and alignment and every block
+/// gets its lifetime recorded when it is freed. `report` renders the histograms.
+///
+pub struct ProfileAlloc<A: UsableSize + Clone = System> {
+    inner           : A,
+    ticks           : AtomicUsize,
+    reallocations   : AtomicUsize,
+    frees           : AtomicUsize,
+    sizes           : [AtomicUsize; PROFILE_BUCKETS],
+    bytes           : [AtomicUsize; PROFILE_BUCKETS],
+    aligns          : [AtomicUsize; PROFILE_BUCKETS],
+    lifetimes       : [AtomicUsize; PROFILE_BUCKETS],
+    births          : SpinLock<Births<A>>,
+}
+
+impl ProfileAlloc {
+    pub const fn new() -> Self { Self::new_in(System) }
+}
+
+impl Default for ProfileAlloc {
+    fn default() -> Self { Self::new() }
+}
+
+fn load(h: &[AtomicUsize; PROFILE_BUCKETS]) -> [usize; PROFILE_BUCKETS] {
+    let mut out = [0; PROFILE_BUCKETS];
+    for (o, c) in out.iter_mut().zip(h.iter()) {
+        *o = c.load(Ordering::Relaxed);
+    }
+    out
+}
+
+impl<A: UsableSize + Clone> ProfileAlloc<A> {
+    pub const fn new_in(inner: A) -> Self {
+        Self {
+            inner,
+            ticks           : AtomicUsize::new(0),
+            reallocations   : AtomicUsize::new(0),
+            frees           : AtomicUsize::new(0),
+            sizes           : [ZERO; PROFILE_BUCKETS],
+            bytes           : [ZERO; PROFILE_BUCKETS],
+            aligns          : [ZERO; PROFILE_BUCKETS],
+            lifetimes       : [ZERO; PROFILE_BUCKETS],
+            births          : SpinLock::new(Births { live: None }),
+        }
+    }
+
+    pub fn allocations(&self) -> usize { self.ticks.load(Ordering::Relaxed) }
+    pub fn reallocations(&self) -> usize { self.reallocations.load(Ordering::Relaxed) }
+    pub fn frees(&self) -> usize { self.frees.load(Ordering::Relaxed) }
+
+    // requests per size bucket, reallocations count under their new size
+    pub fn size_histogram(&self) -> [usize; PROFILE_BUCKETS] { load(&self.sizes) }
+
+    // requests per alignment, bucket i counting alignment 2^i
+    pub fn align_histogram(&self) -> [usize; PROFILE_BUCKETS] { load(&self.aligns) }
+
+    // freed blocks per lifetime bucket
+    pub fn lifetime_histogram(&self) -> [usize; PROFILE_BUCKETS] { load(&self.lifetimes) }
+
+    pub fn write_report<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        writeln!(w, "{} allocation(s), {} reallocation(s), {} free(s)", self.allocations(), self.reallocations(), self.frees())?;
+
+        writeln!(w, "size:")?;
+        let bytes = load(&self.bytes);
+        for (i, &n) in self.size_histogram().iter().enumerate() {
+            let (lo, hi) = bucket_range(i);
+            if n != 0 { writeln!(w, "  {:>10} ..= {:<10} {:>10} {:>14} bytes", lo, hi, n, bytes[i])? }
+        }
+
+        writeln!(w, "alignment:")?;
+        for (i, &n) in self.align_histogram().iter().enumerate() {
+            if n != 0 { writeln!(w, "  {:>10}     {:<10} {:>10}", 1usize << i, "", n)? }
+        }
+
+        writeln!(w, "lifetime (allocations):")?;
+        for (i, &n) in self.lifetime_histogram().iter().enumerate() {
+            let (lo, hi) = bucket_range(i);
+            if n != 0 { writeln!(w, "  {:>10} ..= {:<10} {:>10}", lo, hi, n)? }
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        let _ = self.write_report(&mut s);
+        s
+    }
+
+    fn count(&self, layout: &Layout) {
+        let b = bucket(layout.size());
+        self.sizes[b].fetch_add(1, Ordering::Relaxed);
+        self.bytes[b].fetch_add(layout.size(), Ordering::Relaxed);
+        self.aligns[layout.align().trailing_zeros() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a block whose birth can't be stored is left out of the lifetime histogram
+    // rather than aborting an allocation that succeeded
+    fn born(&self, ptr: *mut u8, tick: usize) {
+        let mut b = self.births.lock();
+        let inner = &self.inner;
+        let _ = b.live.get_or_insert_with(|| HashMap::new_in(inner.clone())).try_set(ptr as usize, tick);
+    }
+
+    fn died(&self, ptr: *mut u8) -> Option<usize> {
+        let mut b = self.births.lock();
+        let m = b.live.as_mut()?;
+        let tick = m.get(ptr as usize).copied();
+        if tick.is_some() { m.remove(ptr as usize) }
+        tick
+    }
+
+    fn record_alloc(&self, ptr: *mut u8, layout: &Layout) {
+        let tick = self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.count(layout);
+        self.born(ptr, tick);
+    }
+}
+
+unsafe impl<A: UsableSize + Clone> GlobalAlloc for ProfileAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = self.inner.alloc(layout);
+        if !p.is_null() { self.record_alloc(p, &layout) }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = self.inner.alloc_zeroed(layout);
+        if !p.is_null() { self.record_alloc(p, &layout) }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(birth) = self.died(ptr) {
+            let now = self.ticks.load(Ordering::Relaxed);
+            self.lifetimes[bucket(now - birth)].fetch_add(1, Ordering::Relaxed);
+        }
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.inner.dealloc(ptr, layout)
+    }
+
+    // the block keeps its birth tick, its new size is counted as a request
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = self.inner.realloc(ptr, layout, new_size);
+        if !p.is_null() {
+            self.reallocations.fetch_add(1, Ordering::Relaxed);
+            self.count(&Layout::from_size_align_unchecked(new_size, layout.align()));
+            if p != ptr {
+                if let Some(birth) = self.died(ptr) { self.born(p, birth) }
+            }
+        }
+        p
+    }
+}
+
+unsafe impl<A: UsableSize + Clone> GlobalAlloc for &ProfileAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl<A: UsableSize + Clone> UsableSize for ProfileAlloc<A> {}
+unsafe impl<A: UsableSize + Clone> UsableSize for &ProfileAlloc<A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        assert!(bucket(0) == 0 && bucket(1) == 0 && bucket(2) == 1 && bucket(3) == 2);
+        assert!(bucket(4) == 2 && bucket(5) == 3 && bucket(usize::MAX) == PROFILE_BUCKETS - 1);
+        for x in [0, 1, 2, 3, 4, 5, 100, 4096, 4097, usize::MAX] {
+            let (lo, hi) = bucket_range(bucket(x));
+            assert!(lo <= x && x <= hi);
+        }
+        // the ranges tile the whole size space without overlapping
+        for i in 1..PROFILE_BUCKETS {
+            assert!(bucket_range(i).0 == bucket_range(i - 1).1 + 1);
+        }
+        assert!(bucket((1 << (usize::BITS - 1)) + 1) == PROFILE_BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_histograms() {
+        let prof = ProfileAlloc::new();
+        {
+            let _a = Box::new_in(0u64, &prof);
+            let _b = Box::new_in(0u64, &prof);
+            let _c = Box::new_in(0u8, &prof);
+            let mut v = Vec::with_capacity_in(3, &prof);
+            v.push(1u32);
+        }
+        assert!(prof.allocations() == 4 && prof.frees() == 4);
+
+        let sizes = prof.size_histogram();
+        assert!(sizes[bucket(8)] == 2 && sizes[bucket(1)] == 1 && sizes[bucket(12)] == 1);
+        let aligns = prof.align_histogram();
+        assert!(aligns[3] == 2 && aligns[2] == 1 && aligns[0] == 1);
+
+        // drop order is v, _c, _b, _a: they lived 1, 2, 3 and 4 ticks
+        let lifetimes = prof.lifetime_histogram();
+        assert!(lifetimes[bucket(1)] == 1 && lifetimes[bucket(2)] == 1 && lifetimes[bucket(4)] == 2);
+    }
+
+    #[test]
+    fn test_realloc_keeps_birth() {
+        let prof = ProfileAlloc::new();
+        {
+            let mut v = Vec::new_in(&prof);
+            for i in 0..100 {
+                v.push(i);
+            }
+        }
+        assert!(prof.allocations() == 1);
+        assert!(prof.reallocations() > 0);
+        assert!(prof.lifetime_histogram()[bucket(1)] == 1);
+    }
+
+    #[test]
+    fn test_births_out_of_memory() {
+        // the block is the first allocation, the birth table the second
+        let fail = FailAlloc::new(FailPolicy::Nth(2));
+        let prof = ProfileAlloc::new_in(&fail);
+        {
+            let _b = Box::new_in(0u64, &prof);
+        }
+        assert!(prof.allocations() == 1 && prof.frees() == 1);
+        assert!(prof.lifetime_histogram().iter().sum::<usize>() == 0);
+    }
+
+    #[test]
+    fn test_report() {
+        let prof = ProfileAlloc::new();
+        let _b = Box::new_in([0u8; 100], &prof);
+        let report = prof.report();
+        assert!(report.as_str().contains("1 allocation(s), 0 reallocation(s), 0 free(s)"));
+        assert!(report.as_str().contains("65 ..= 128"));
+    }
+}
@@ -0,0 +1,325 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use core::sync::atomic::*;
+use crate::*;
+use crate::spin::*;
+
+//
+// Geometric size classes: multiples of 16 up to 128, then four classes per power of
+// two (160, 192, 224, 256, 320...) up to 32KiB, so at most 25% of a block is wasted.
+// Blocks are carved from spans mapped from the OS, spans are never given back, which
+// keeps the layout of the heap identical from one run (and one libc) to the next.
+// Bigger blocks are mapped on their own.
+//
+pub const SIZE_CLASSES      : usize = 40;
+pub const MAX_CLASS_SIZE    : usize = 32 * 1024;
+pub const SPAN_SIZE         : usize = 64 * 1024;
+
+const PAGE_SIZE : usize = 4096;
+
+struct FreeBlock {
+    next    : *mut FreeBlock,
+}
+
+struct Class {
+    free    : *mut FreeBlock,
+    bump    : usize,        // uncarved part of the current span
+    end     : usize,
+}
+
+unsafe impl Send for Class {}
+
+// repeated to build the class array in a const fn, every copy is a fresh value
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CLASS : SpinLock<Class> = SpinLock::new(Class { free: ptr::null_mut(), bump: 0, end: 0 });
+
+#[inline]
+fn page_align(size: usize) -> Option<usize> {
+    Some(size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+fn class_size(class: usize) -> usize {
+    if class < 8 {
+        16 * (class + 1)
+    } else {
+        let p = 7 + (class - 8) / 4;
+        let sub = (class - 8) % 4 + 1;
+        (1 << p) + sub * (1 << (p - 2))
+    }
+}
+
+// smallest class holding `layout`, its size being a multiple of the alignment
+fn class_of(layout: &Layout) -> Option<usize> {
+    let size = layout.size();
+    if size > MAX_CLASS_SIZE || layout.align() > PAGE_SIZE { return None }
+
+    let mut class = if size <= 128 {
+        (cmp::max(size, 1) + 15) / 16 - 1
+    } else {
+        let p = (usize::BITS - (size - 1).leading_zeros() - 1) as usize;
+        let sub = (size - (1 << p) + (1 << (p - 2)) - 1) >> (p - 2);
+        8 + (p - 7) * 4 + sub - 1
+    };
+    while class < SIZE_CLASSES && class_size(class) & (layout.align() - 1) != 0 { class += 1 }
+    if class < SIZE_CLASSES { Some(class) } else { None }
+}
+
+///
+/// General purpose allocator with per size class free lists over spans of pages
+/// mapped from the OS, blocks above `MAX_CLASS_SIZE` are mapped directly. It does
+/// not depend on the libc allocator and behaves the same whatever the libc.
+///
+pub struct SizeClassAlloc {
+    classes         : [SpinLock<Class>; SIZE_CLASSES],
+    mapped_bytes    : AtomicUsize,
+}
+
+impl SizeClassAlloc {
+    pub const fn new() -> Self {
+        Self {
+            classes         : [EMPTY_CLASS; SIZE_CLASSES],
+            mapped_bytes    : AtomicUsize::new(0),
+        }
+    }
+
+    // bytes currently mapped from the OS, spans and large blocks
+    pub fn mapped_bytes(&self) -> usize { self.mapped_bytes.load(Ordering::Relaxed) }
+
+    unsafe fn map(&self, size: usize) -> *mut u8 {
+        let p = crate::os::map_pages(size);
+        if !p.is_null() { self.mapped_bytes.fetch_add(size, Ordering::Relaxed); }
+        p
+    }
+
+    unsafe fn unmap(&self, p: *mut u8, size: usize) {
+        crate::os::unmap_pages(p, size);
+        self.mapped_bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    unsafe fn alloc_small(&self, class: usize) -> *mut u8 {
+        let size = class_size(class);
+        let mut c = self.classes[class].lock();
+        if !c.free.is_null() {
+            let b = c.free;
+            c.free = (*b).next;
+            return b as *mut u8
+        }
+
+        if c.bump + size > c.end {
+            let span = self.map(SPAN_SIZE);
+            if span.is_null() { return span }
+            c.bump = span as usize;
+            c.end = span as usize + SPAN_SIZE;
+        }
+        let p = c.bump;
+        c.bump += size;
+        p as *mut u8
+    }
+
+    unsafe fn dealloc_small(&self, p: *mut u8, class: usize) {
+        let mut c = self.classes[class].lock();
+        let b = p as *mut FreeBlock;
+        (*b).next = c.free;
+        c.free = b;
+    }
+
+    // mmap only guarantees page alignment: map more and trim both ends for bigger ones
+    unsafe fn alloc_large(&self, layout: Layout) -> *mut u8 {
+        let size = match page_align(layout.size()) { Some(s) => s, None => return ptr::null_mut() };
+        if layout.align() <= PAGE_SIZE { return self.map(size) }
+
+        let total = match size.checked_add(layout.align()) { Some(t) => t, None => return ptr::null_mut() };
+        let base = self.map(total);
+        if base.is_null() { return base }
+        let start = (base as usize + layout.align() - 1) & !(layout.align() - 1);
+        let head = start - base as usize;
+        if head != 0 { self.unmap(base, head) }
+        if total - head - size != 0 { self.unmap((start + size) as *mut u8, total - head - size) }
+        start as *mut u8
+    }
+
+    unsafe fn dealloc_large(&self, p: *mut u8, layout: Layout) {
+        self.unmap(p, page_align(layout.size()).unwrap_unchecked())
+    }
+}
+
+impl Default for SizeClassAlloc {
+    fn default() -> Self { Self::new() }
+}
+
+unsafe impl GlobalAlloc for SizeClassAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match class_of(&layout) {
+            Some(class) => self.alloc_small(class),
+            None => self.alloc_large(layout),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match class_of(&layout) {
+            Some(class) => {
+                let p = self.alloc_small(class);
+                if !p.is_null() { ptr::write_bytes(p, 0, layout.size()) }
+                p
+            },
+            // fresh mappings are already zeroed
+            None => self.alloc_large(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match class_of(&layout) {
+            Some(class) => self.dealloc_small(ptr, class),
+            None => self.dealloc_large(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (class_of(&layout), class_of(&new_layout)) {
+            (Some(a), Some(b)) if a == b => ptr,
+            (None, None) if page_align(layout.size()) == page_align(new_size) => ptr,
+            _ => realloc_fallback(self, ptr, layout, new_size),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for &SizeClassAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl UsableSize for SizeClassAlloc {
+    unsafe fn usable_size(&self, _ptr: *mut u8, layout: Layout) -> usize {
+        match class_of(&layout) {
+            Some(class) => class_size(class),
+            None => page_align(layout.size()).unwrap_or(layout.size()),
+        }
+    }
+}
+
+unsafe impl UsableSize for &SizeClassAlloc {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { (*self).usable_size(ptr, layout) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classes() {
+        assert!(class_size(SIZE_CLASSES - 1) == MAX_CLASS_SIZE);
+        for class in 1..SIZE_CLASSES {
+            assert!(class_size(class) > class_size(class - 1));
+            assert!(class_size(class) % 16 == 0);
+        }
+        for size in 0..=MAX_CLASS_SIZE {
+            for align in [1, 8, 16, 64, 4096] {
+                let c = class_of(&Layout::from_size_align(size, align).unwrap()).unwrap();
+                assert!(class_size(c) >= size && class_size(c) % align == 0);
+                assert!(c == 0 || class_size(c - 1) < size || class_size(c - 1) % align != 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reuse() {
+        let heap = SizeClassAlloc::new();
+        let a = &heap;
+        unsafe {
+            let p = a.alloc(Layout::from_size_align(200, 8).unwrap());
+            a.dealloc(p, Layout::from_size_align(200, 8).unwrap());
+            let q = a.alloc(Layout::from_size_align(210, 4).unwrap());
+            assert!(p == q);
+            let q = a.realloc(q, Layout::from_size_align(210, 4).unwrap(), 220);
+            assert!(p == q);
+            a.dealloc(q, Layout::from_size_align(220, 4).unwrap());
+        }
+        assert!(heap.mapped_bytes() == SPAN_SIZE);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let heap = SizeClassAlloc::new();
+        let a = &heap;
+        for i in 0..16 {
+            let l = Layout::from_size_align(100, 1 << i).unwrap();
+            unsafe {
+                let p = a.alloc(l);
+                assert!(!p.is_null() && (p as usize) & (l.align() - 1) == 0);
+                a.dealloc(p, l);
+            }
+        }
+    }
+
+    #[test]
+    fn test_large() {
+        let heap = SizeClassAlloc::new();
+        let a = &heap;
+        let l = Layout::from_size_align(MAX_CLASS_SIZE + 1, 8).unwrap();
+        unsafe {
+            let p = a.alloc_zeroed(l);
+            assert!(*p.add(MAX_CLASS_SIZE) == 0);
+            *p = 5;
+            let p = a.realloc(p, l, 4 * MAX_CLASS_SIZE);
+            assert!(*p == 5);
+            let p = a.realloc(p, Layout::from_size_align(4 * MAX_CLASS_SIZE, 8).unwrap(), 64);
+            assert!(*p == 5);
+            a.dealloc(p, Layout::from_size_align(64, 8).unwrap());
+
+            let l = Layout::from_size_align(3 * PAGE_SIZE, 1 << 16).unwrap();
+            let p = a.alloc(l);
+            assert!((p as usize) & ((1 << 16) - 1) == 0);
+            a.dealloc(p, l);
+        }
+        assert!(heap.mapped_bytes() == SPAN_SIZE);
+    }
+
+    static HEAP : SizeClassAlloc = SizeClassAlloc::new();
+
+    #[test]
+    fn test_collections() {
+        let mut hm = HashMap::new_in(&HEAP);
+        for i in 0..1000 {
+            let mut s = String::new_in(&HEAP);
+            s.push_str("value");
+            hm.set(i, s);
+        }
+        for i in 0..1000 {
+            assert!(*hm.get(i).unwrap() == "value");
+        }
+    }
+}
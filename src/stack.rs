@@ -0,0 +1,181 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use core::cell::Cell;
+use crate::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Marker(usize);
+
+///
+/// LIFO allocator over a fixed region: blocks are bumped off the top, freeing the
+/// topmost block pops it, and `rewind` releases everything allocated since a `Marker`.
+///
+pub struct StackAlloc {
+    base    : *mut u8,
+    size    : usize,
+    top     : Cell<usize>,      // offset of the first free byte
+}
+
+impl StackAlloc {
+    pub fn with_capacity(size: usize) -> Self {
+        let base = match Layout::from_size_align(size, MIN_ALIGN) {
+            Ok(layout) if size > 0 => unsafe { sysalloc.alloc(layout) },
+            _ => panic!("unable to create layout")
+        };
+        if base.is_null() { panic!("unable to allocate stack region") }
+        Self { base, size, top: Cell::new(0) }
+    }
+
+    pub fn marker(&self) -> Marker { Marker(self.top.get()) }
+
+    // collections allocating from the stack borrow it, so `&mut` guarantees none of
+    // them still owns a block above the marker
+    pub fn rewind(&mut self, marker: Marker) {
+        debug_assert!(marker.0 <= self.top.get(), "marker rewound out of order");
+        self.top.set(marker.0);
+    }
+
+    pub fn used(&self) -> usize { self.top.get() }
+    pub fn capacity(&self) -> usize { self.size }
+
+    #[inline]
+    fn offset(&self, ptr: *mut u8) -> usize { ptr as usize - self.base as usize }
+}
+
+impl Drop for StackAlloc {
+    fn drop(&mut self) {
+        unsafe { sysalloc.dealloc(self.base, Layout::from_size_align_unchecked(self.size, MIN_ALIGN)) }
+    }
+}
+
+unsafe impl GlobalAlloc for &StackAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let addr    = self.base as usize + self.top.get();
+        let start   = (addr + layout.align() - 1) & !(layout.align() - 1);
+        let end     = start - self.base as usize + layout.size();
+        if end > self.size { return ptr::null_mut() }
+
+        self.top.set(end);
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let end = self.offset(ptr) + layout.size();
+        debug_assert!(end <= self.top.get(), "block freed after its frame was rewound");
+
+        // blocks below the top are reclaimed by the next rewind
+        if end == self.top.get() {
+            self.top.set(self.offset(ptr));
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let end = self.offset(ptr) + layout.size();
+        debug_assert!(end <= self.top.get(), "block reallocated after its frame was rewound");
+
+        if end == self.top.get() && self.offset(ptr) + new_size <= self.size {
+            self.top.set(self.offset(ptr) + new_size);
+            ptr
+        } else {
+            realloc_fallback(self, ptr, layout, new_size)
+        }
+    }
+}
+
+unsafe impl UsableSize for &StackAlloc {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let mut stack = StackAlloc::with_capacity(64 * 1024);
+        let l = Layout::new::<u64>();
+        let outer = unsafe { (&stack).alloc(l) as *mut u64 };
+        unsafe { *outer = 1 };
+
+        let m = stack.marker();
+        {
+            let mut v = Vec::new_in(&stack);
+            let mut s = String::new_in(&stack);
+            for i in 0..100 {
+                v.push(i);
+                s.push(b'a');
+            }
+            assert!(v[99] == 99);
+            assert!(s.len() == 100);
+        }
+        stack.rewind(m);
+        assert!(stack.used() == m.0);
+        assert!(unsafe { *outer } == 1);
+
+        // the next frame starts where the rewound one did
+        let mut v = Vec::<u8, _>::with_capacity_in(16, &stack);
+        v.push(0);
+        assert!(v.as_slice().as_ptr() as usize == stack.base as usize + m.0);
+    }
+
+    #[test]
+    fn test_lifo_free() {
+        let stack = StackAlloc::with_capacity(1024);
+        let a = &stack;
+        let l = Layout::new::<u64>();
+        let p0 = unsafe { a.alloc(l) };
+        let p1 = unsafe { a.alloc(l) };
+        unsafe { a.dealloc(p1, l) };
+        unsafe { a.dealloc(p0, l) };
+        assert!(stack.used() == 0);
+    }
+
+    #[test]
+    fn test_out_of_memory() {
+        let stack = StackAlloc::with_capacity(64);
+        let a = &stack;
+        assert!(!unsafe { a.alloc(Layout::from_size_align(64, 1).unwrap()) }.is_null());
+        assert!(unsafe { a.alloc(Layout::from_size_align(1, 1).unwrap()) }.is_null());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "marker rewound out of order")]
+    fn test_rewind_out_of_order() {
+        let mut stack = StackAlloc::with_capacity(1024);
+        let outer = stack.marker();
+        let _ = unsafe { (&stack).alloc(Layout::new::<u64>()) };
+        let inner = stack.marker();
+        stack.rewind(outer);
+        stack.rewind(inner);
+    }
+}
@@ -0,0 +1,230 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::alloc::*;
+use core::sync::atomic::*;
+use crate::os::System;
+use crate::UsableSize;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub allocations     : usize,
+    pub deallocations   : usize,
+    pub reallocations   : usize,
+    pub live_bytes      : usize,
+    pub peak_bytes      : usize,
+}
+
+// change between two snapshots, `live_bytes` can go down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsDiff {
+    pub allocations     : usize,
+    pub deallocations   : usize,
+    pub reallocations   : usize,
+    pub live_bytes      : isize,
+    pub peak_bytes      : usize,
+}
+
+impl Stats {
+    pub fn diff(&self, earlier: &Stats) -> StatsDiff {
+        StatsDiff {
+            allocations     : self.allocations - earlier.allocations,
+            deallocations   : self.deallocations - earlier.deallocations,
+            reallocations   : self.reallocations - earlier.reallocations,
+            live_bytes      : self.live_bytes as isize - earlier.live_bytes as isize,
+            peak_bytes      : self.peak_bytes.saturating_sub(earlier.peak_bytes),
+        }
+    }
+}
+
+///
+/// Wrapper counting the calls made to the inner allocator and the bytes it holds.
+///
+pub struct StatsAlloc<A: GlobalAlloc = System> {
+    inner           : A,
+    allocations     : AtomicUsize,
+    deallocations   : AtomicUsize,
+    reallocations   : AtomicUsize,
+    live_bytes      : AtomicUsize,
+    peak_bytes      : AtomicUsize,
+}
+
+impl StatsAlloc {
+    pub const fn new() -> Self { Self::new_in(System) }
+}
+
+impl Default for StatsAlloc {
+    fn default() -> Self { Self::new() }
+}
+
+impl<A: GlobalAlloc> StatsAlloc<A> {
+    pub const fn new_in(inner: A) -> Self {
+        Self {
+            inner,
+            allocations     : AtomicUsize::new(0),
+            deallocations   : AtomicUsize::new(0),
+            reallocations   : AtomicUsize::new(0),
+            live_bytes      : AtomicUsize::new(0),
+            peak_bytes      : AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &A { &self.inner }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            allocations     : self.allocations.load(Ordering::SeqCst),
+            deallocations   : self.deallocations.load(Ordering::SeqCst),
+            reallocations   : self.reallocations.load(Ordering::SeqCst),
+            live_bytes      : self.live_bytes.load(Ordering::SeqCst),
+            peak_bytes      : self.peak_bytes.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn reset_peak(&self) {
+        self.peak_bytes.store(self.live_bytes.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    fn grow(&self, size: usize) {
+        let live = self.live_bytes.fetch_add(size, Ordering::SeqCst) + size;
+        self.peak_bytes.fetch_max(live, Ordering::SeqCst);
+    }
+
+    fn shrink(&self, size: usize) {
+        self.live_bytes.fetch_sub(size, Ordering::SeqCst);
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for StatsAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = self.inner.alloc(layout);
+        if !p.is_null() {
+            self.allocations.fetch_add(1, Ordering::SeqCst);
+            self.grow(layout.size());
+        }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = self.inner.alloc_zeroed(layout);
+        if !p.is_null() {
+            self.allocations.fetch_add(1, Ordering::SeqCst);
+            self.grow(layout.size());
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.deallocations.fetch_add(1, Ordering::SeqCst);
+        self.shrink(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = self.inner.realloc(ptr, layout, new_size);
+        if !p.is_null() {
+            self.reallocations.fetch_add(1, Ordering::SeqCst);
+            if new_size > layout.size() {
+                self.grow(new_size - layout.size());
+            } else {
+                self.shrink(layout.size() - new_size);
+            }
+        }
+        p
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for &StatsAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl<A: GlobalAlloc> UsableSize for StatsAlloc<A> {}
+unsafe impl<A: GlobalAlloc> UsableSize for &StatsAlloc<A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_counts() {
+        let stats = StatsAlloc::new();
+        let before = stats.stats();
+        {
+            let mut v = Vec::new_in(&stats);
+            for i in 0..100u32 {
+                v.push(i);
+            }
+            let _b = Box::new_in(1u64, &stats);
+            let s = stats.stats().diff(&before);
+            assert!(s.allocations == 2);
+            assert!(s.reallocations == 3);
+            assert!(s.deallocations == 0);
+            assert!(s.live_bytes == 128 * 4 + 8);
+            assert!(s.peak_bytes == 128 * 4 + 8);
+        }
+        let s = stats.stats().diff(&before);
+        assert!(s.allocations == s.deallocations);
+        assert!(s.live_bytes == 0);
+    }
+
+    #[test]
+    fn test_presized_hashmap() {
+        let stats = StatsAlloc::new();
+        let mut hm = HashMap::with_capacity_in(100, &stats);
+        let before = stats.stats();
+        for i in 0..100 {
+            hm.set(i, i * 2);
+        }
+        let s = stats.stats().diff(&before);
+        assert!(s.allocations == 0);
+        assert!(s.reallocations == 0);
+    }
+
+    #[test]
+    fn test_realloc() {
+        let stats = StatsAlloc::new();
+        let a = &stats;
+        let l = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let p = a.alloc(l);
+            let p = a.realloc(p, l, 256);
+            assert!(stats.stats().live_bytes == 256);
+            assert!(stats.stats().reallocations == 1);
+            a.dealloc(p, Layout::from_size_align(256, 8).unwrap());
+        }
+        assert!(stats.stats().live_bytes == 0);
+        assert!(stats.stats().peak_bytes == 256);
+    }
+}
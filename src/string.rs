@@ -1,61 +1,106 @@
 use crate::vec::*;
+use crate::os::System;
 use ::core::*;
-use ::core::cmp::*;
+use ::core::alloc::GlobalAlloc;
+use crate::UsableSize;
 use crate::hash::*;
 use core::fmt::{Arguments, Write};
 
 #[repr(C)]
-pub struct String {
-    data    : Vec<u8>
+pub struct String<A: GlobalAlloc = System> {
+    data    : Vec<u8, A>
 }
 
 impl String {
+    #[track_caller]
     pub fn with_capacity(c: usize) -> Self {
         Self { data: Vec::with_capacity(c) }
     }
 
     pub fn new() -> Self { Self { data: Vec::new() } }
+
+    #[allow(clippy::should_implement_trait)]
+    #[track_caller]
     pub fn from(s: &str) -> Self {
-        let mut st = Self::new();
-        for c in s.bytes() {
-            st.data.push(c);
-        }
-        st
+        Self::from_in(s, System)
     }
 
+    pub fn from_raw_parts(ptr: *mut u8, len: usize, cap: usize) -> Self {
+        Self { data : Vec::from_raw_parts(ptr, len, cap) }
+    }
+}
+
+impl<A: GlobalAlloc> String<A> {
+    pub fn new_in(alloc: A) -> Self { Self { data: Vec::new_in(alloc) } }
+
     pub fn as_str(&self) -> &str {
         ::core::str::from_utf8(self.data.as_slice()).expect("Error getting string out")
     }
 
+    pub fn into_bytes(self) -> Vec<u8, A> { self.data }
+    pub fn as_bytes(&self) -> &[u8] { self.data.as_slice() }
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] { self.data.as_mut_slice() }
+    pub fn as_mut_vec(&mut self) -> &mut Vec<u8, A> { &mut self.data }
+
+    pub fn len(&self) -> usize { self.data.len() }
+
+    pub fn allocator(&self) -> &A { self.data.allocator() }
+
+    pub fn from_raw_parts_in(ptr: *mut u8, len: usize, cap: usize, alloc: A) -> Self {
+        Self { data : Vec::from_raw_parts_in(ptr, len, cap, alloc) }
+    }
+}
+
+impl<A: UsableSize> String<A> {
+    #[track_caller]
+    pub fn with_capacity_in(c: usize, alloc: A) -> Self {
+        Self { data: Vec::with_capacity_in(c, alloc) }
+    }
+
+    #[track_caller]
+    pub fn from_in(s: &str, alloc: A) -> Self {
+        let mut st = Self::new_in(alloc);
+        st.push_str(s);
+        st
+    }
+
+    #[track_caller]
     pub fn push(&mut self, u: u8) {
         self.data.push(u);
     }
 
-    pub fn into_bytes(self) -> Vec<u8> { self.data }
-    pub fn as_bytes(&self) -> &[u8] { self.data.as_slice() }
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] { self.data.as_mut_slice() }
-    pub fn as_mut_vec(&mut self) -> &mut Vec<u8> { &mut self.data }
-
+    #[track_caller]
     pub fn push_str(&mut self, s: &str) {
+        self.data.reserve(s.len());
         for c in s.bytes() {
             self.data.push(c);
         }
     }
 
-    pub fn len(&self) -> usize { self.data.len() }
+    // on failure the string is left untouched
+    #[track_caller]
+    pub fn try_push_str(&mut self, s: &str) -> Result<(), crate::AllocError> {
+        self.data.try_reserve(s.len())?;
+        for c in s.bytes() {
+            self.data.push(c);
+        }
+        Ok(())
+    }
+}
 
-    pub fn split(&self, pattern: &str) -> Split {
-        let mut v = Vec::<String>::new();
+impl<A: UsableSize + Clone> String<A> {
+    pub fn split(&self, pattern: &str) -> Split<A> {
+        let mut v = Vec::<String<A>, A>::new_in(self.allocator().clone());
         let mut i = 0;
         let ss = self.as_str();
         let ss_len = ss.len();
         let mut chars = ss.chars();
         loop {
-            let mut st = String::new();
+            let mut st = String::new_in(self.allocator().clone());
             loop {
                 match chars.next() {
                     Some(c) if pattern.contains(c) => {
-                        if st.as_str().len() > 0 {
+                        if !st.as_str().is_empty() {
                             v.push(st);
                         }
                         i += 1;
@@ -66,7 +111,7 @@ impl String {
                         i += 1;
                     },
                     None => {
-                        if st.as_str().len() > 0 {
+                        if !st.as_str().is_empty() {
                             v.push(st);
                         }
                         break
@@ -77,25 +122,21 @@ impl String {
                 break
             }
         }
-        Split { v: v, idx: 0 }
+        Split { v, idx: 0 }
     }
 
-    pub fn lines(&self) -> Lines {
+    pub fn lines(&self) -> Lines<A> {
         Lines(self.split("\n"))
     }
-
-    pub fn from_raw_parts(ptr: *mut u8, len: usize, cap: usize) -> Self {
-        Self { data : Vec::from_raw_parts(ptr, len, cap) }
-    }
 }
 
-pub struct Split {
-    v: Vec<String>,
+pub struct Split<A: GlobalAlloc + Clone = System> {
+    v: Vec<String<A>, A>,
     idx: usize,
 }
 
-impl Iterator for Split  {
-    type Item = String;
+impl<A: UsableSize + Clone> Iterator for Split<A> {
+    type Item = String<A>;
     fn next(&mut self) -> Option<Self::Item> {
         if self.idx < self.v.len() {
             self.idx += 1;
@@ -105,9 +146,9 @@ impl Iterator for Split  {
     }
 }
 
-pub struct Lines(Split);
-impl Iterator for Lines {
-    type Item = String;
+pub struct Lines<A: GlobalAlloc + Clone = System>(Split<A>);
+impl<A: UsableSize + Clone> Iterator for Lines<A> {
+    type Item = String<A>;
     fn next(&mut self) -> Option<Self::Item> { self.0.next() }
 }
 
@@ -116,48 +157,33 @@ pub trait Append<T> {
 }
 
 
-impl Append<&String> for String {
-    fn append(&mut self, s: &String) {
-        for c in s.as_str().bytes() {
-            self.data.push(c);
-        }
+impl<A: UsableSize, B: GlobalAlloc> Append<&String<B>> for String<A> {
+    fn append(&mut self, s: &String<B>) {
+        self.push_str(s.as_str());
     }
 }
 
-impl PartialEq<String> for String {
-    fn eq(&self, other: &Self) -> bool {
-        let ls = self.data.len();
-        let lo = other.data.len();
-        if ls != lo { return false }
-        for i in 0..self.data.len() {
-            if self.data[i] != other.data[i] { return false }
-        }
-        true
+impl<A: GlobalAlloc, B: GlobalAlloc> PartialEq<String<B>> for String<A> {
+    fn eq(&self, other: &String<B>) -> bool {
+        self.as_bytes() == other.as_bytes()
     }
 }
 
-impl Eq for String {}
+impl<A: GlobalAlloc> Eq for String<A> {}
 
-impl PartialEq<&str> for String {
+impl<A: GlobalAlloc> PartialEq<&str> for String<A> {
     fn eq(&self, other: &&str) -> bool {
-        let ob = (*other).as_bytes();
-        let ls = self.data.len();
-        let lo = ob.len();
-        if ls != lo { return false }
-        for i in 0..self.data.len() {
-            if self.data[i] != ob[i] { return false }
-        }
-        true
+        self.as_bytes() == other.as_bytes()
     }
 }
 
-impl Clone for String {
+impl<A: UsableSize + Clone> Clone for String<A> {
     fn clone(&self) -> Self {
-        String::from(self.as_str())
+        String::from_in(self.as_str(), self.allocator().clone())
     }
 }
 
-impl fmt::Write for String {
+impl<A: UsableSize> fmt::Write for String<A> {
     #[inline]
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.push_str(s);
@@ -171,13 +197,13 @@ impl fmt::Write for String {
     }
 }
 
-impl fmt::Display for String {
+impl<A: GlobalAlloc> fmt::Display for String<A> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.as_str())
     }
 }
 
-impl Hash for String {
+impl<A: GlobalAlloc> Hash for String<A> {
     fn hash(&self) -> usize {
         self.as_bytes().hash()
     }
@@ -243,4 +269,12 @@ mod tests {
         assert_eq!(ss1[1].as_str(), "something is different");
     }
 
+    #[test]
+    fn test_try_push_str() {
+        let stack = crate::StackAlloc::with_capacity(16);
+        let mut s = String::new_in(&stack);
+        assert!(s.try_push_str("hello").is_ok());
+        assert!(s.try_push_str("a string too long for the stack").is_err());
+        assert!(s == "hello");
+    }
 }
@@ -0,0 +1,345 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use crate::*;
+use crate::spin::*;
+
+//
+// Small blocks are kept on per-thread free lists, one list per power of two size
+// class. There is no thread local storage in core, so a thread finds its cache from
+// the thread pointer register where libc sets it up, and elsewhere from its stack
+// address. Threads only share a cache (each has its own lock) when their keys collide.
+//
+pub const TCACHE_CLASSES    : usize = 8;                // 16 up to 2048 bytes
+pub const TCACHE_MAX_SIZE   : usize = MIN_CLASS << (TCACHE_CLASSES - 1);
+pub const TCACHE_LIMIT      : usize = 64;               // blocks kept per class before giving half back
+pub const TCACHE_BATCH      : usize = 16;               // blocks taken from the backend on a miss
+
+const MIN_CLASS     : usize = 16;
+const SLOTS_LOG2    : usize = 5;
+const SLOTS         : usize = 1 << SLOTS_LOG2;
+
+struct FreeBlock {
+    next    : *mut FreeBlock,
+}
+
+struct Cache {
+    heads   : [*mut FreeBlock; TCACHE_CLASSES],
+    counts  : [usize; TCACHE_CLASSES],
+}
+
+unsafe impl Send for Cache {}
+
+// every slot starts from its own copy of an empty cache
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CACHE : SpinLock<Cache> = SpinLock::new(Cache::new());
+
+impl Cache {
+    const fn new() -> Self {
+        Self { heads: [ptr::null_mut(); TCACHE_CLASSES], counts: [0; TCACHE_CLASSES] }
+    }
+
+    unsafe fn push(&mut self, class: usize, p: *mut u8) {
+        let b = p as *mut FreeBlock;
+        (*b).next = self.heads[class];
+        self.heads[class] = b;
+        self.counts[class] += 1;
+    }
+
+    unsafe fn pop(&mut self, class: usize) -> *mut u8 {
+        let b = self.heads[class];
+        if !b.is_null() {
+            self.heads[class] = (*b).next;
+            self.counts[class] -= 1;
+        }
+        b as *mut u8
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "libc", target_arch = "x86_64"))]
+#[inline(always)]
+fn thread_key() -> usize {
+    // the thread control block starts with a pointer to itself
+    let tp: usize;
+    unsafe { arch::asm!("mov {}, fs:0", out(reg) tp, options(nostack, readonly, preserves_flags)) };
+    tp
+}
+
+#[cfg(all(target_os = "linux", feature = "libc", target_arch = "aarch64"))]
+#[inline(always)]
+fn thread_key() -> usize {
+    let tp: usize;
+    unsafe { arch::asm!("mrs {}, tpidr_el0", out(reg) tp, options(nomem, nostack, preserves_flags)) };
+    tp
+}
+
+// a deep call chain can move the thread to the next slot, blocks are still freed correctly
+#[cfg(not(all(target_os = "linux", feature = "libc", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+#[inline(always)]
+fn thread_key() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize >> 20
+}
+
+// thread control blocks and stacks tend to sit at regular distances, spread them with a fibonacci hash
+#[inline]
+fn slot_index() -> usize {
+    thread_key().wrapping_mul(0x9e37_79b9_7f4a_7c15u64 as usize) >> (usize::BITS as usize - SLOTS_LOG2)
+}
+
+#[inline]
+fn class_of(layout: &Layout) -> Option<usize> {
+    if layout.size() > TCACHE_MAX_SIZE || layout.align() > MIN_ALIGN { return None }
+    let size = cmp::max(layout.size(), MIN_CLASS).next_power_of_two();
+    Some((size.trailing_zeros() - MIN_CLASS.trailing_zeros()) as usize)
+}
+
+#[inline]
+fn class_layout(class: usize) -> Layout {
+    unsafe { Layout::from_size_align_unchecked(MIN_CLASS << class, MIN_ALIGN) }
+}
+
+///
+/// Caching front-end: small blocks freed by a thread are kept for its next
+/// allocations of the same size class, the inner allocator is only called when a
+/// cache runs empty or overflows. Blocks bigger than `TCACHE_MAX_SIZE` or aligned
+/// beyond `MIN_ALIGN` go straight through.
+///
+pub struct ThreadCache<A: GlobalAlloc = System> {
+    inner   : A,
+    caches  : [SpinLock<Cache>; SLOTS],
+}
+
+impl ThreadCache {
+    pub const fn new() -> Self { Self::new_in(System) }
+}
+
+impl Default for ThreadCache {
+    fn default() -> Self { Self::new() }
+}
+
+impl<A: GlobalAlloc> ThreadCache<A> {
+    pub const fn new_in(inner: A) -> Self {
+        Self { inner, caches: [EMPTY_CACHE; SLOTS] }
+    }
+
+    pub fn inner(&self) -> &A { &self.inner }
+
+    // blocks currently held in the caches
+    pub fn cached_blocks(&self) -> usize {
+        self.caches.iter().map(|c| c.lock().counts.iter().sum::<usize>()).sum()
+    }
+
+    // give every cached block back to the inner allocator
+    pub fn flush(&self) {
+        for c in self.caches.iter() {
+            let mut cache = c.lock();
+            for class in 0..TCACHE_CLASSES {
+                loop {
+                    let p = unsafe { cache.pop(class) };
+                    if p.is_null() { break }
+                    unsafe { self.inner.dealloc(p, class_layout(class)) }
+                }
+            }
+        }
+    }
+
+    unsafe fn alloc_small(&self, class: usize) -> *mut u8 {
+        let mut cache = self.caches[slot_index()].lock();
+        let p = cache.pop(class);
+        if !p.is_null() { return p }
+
+        // refill, keeping all but the block handed out
+        let layout = class_layout(class);
+        let p = self.inner.alloc(layout);
+        if p.is_null() { return p }
+        for _ in 1..TCACHE_BATCH {
+            let q = self.inner.alloc(layout);
+            if q.is_null() { break }
+            cache.push(class, q);
+        }
+        p
+    }
+
+    unsafe fn dealloc_small(&self, p: *mut u8, class: usize) {
+        let mut cache = self.caches[slot_index()].lock();
+        cache.push(class, p);
+        if cache.counts[class] > TCACHE_LIMIT {
+            while cache.counts[class] > TCACHE_LIMIT / 2 {
+                let q = cache.pop(class);
+                self.inner.dealloc(q, class_layout(class));
+            }
+        }
+    }
+}
+
+impl<A: GlobalAlloc> Drop for ThreadCache<A> {
+    fn drop(&mut self) { self.flush() }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ThreadCache<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match class_of(&layout) {
+            Some(class) => self.alloc_small(class),
+            None => self.inner.alloc(layout),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match class_of(&layout) {
+            Some(class) => {
+                let p = self.alloc_small(class);
+                if !p.is_null() { ptr::write_bytes(p, 0, layout.size()) }
+                p
+            },
+            None => self.inner.alloc_zeroed(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match class_of(&layout) {
+            Some(class) => self.dealloc_small(ptr, class),
+            None => self.inner.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (class_of(&layout), class_of(&new_layout)) {
+            (Some(a), Some(b)) if a == b => ptr,
+            (None, None) => self.inner.realloc(ptr, layout, new_size),
+            _ => realloc_fallback(self, ptr, layout, new_size),
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for &ThreadCache<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl<A: GlobalAlloc> UsableSize for ThreadCache<A> {
+    unsafe fn usable_size(&self, _ptr: *mut u8, layout: Layout) -> usize {
+        match class_of(&layout) {
+            Some(class) => class_layout(class).size(),
+            None => layout.size(),
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> UsableSize for &ThreadCache<A> {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { (*self).usable_size(ptr, layout) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+
+    #[test]
+    fn test_reuse() {
+        let stats = StatsAlloc::new();
+        let cache = ThreadCache::new_in(&stats);
+        let a = &cache;
+        let l = Layout::from_size_align(24, 8).unwrap();
+        unsafe {
+            let p = a.alloc(l);
+            assert!(stats.stats().allocations == TCACHE_BATCH);
+            a.dealloc(p, l);
+            let q = a.alloc(Layout::from_size_align(32, 16).unwrap());
+            assert!(p == q);
+            assert!(stats.stats().allocations == TCACHE_BATCH);
+            a.dealloc(q, Layout::from_size_align(32, 16).unwrap());
+        }
+        drop(cache);
+        assert!(stats.stats().live_bytes == 0);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let stats = StatsAlloc::new();
+        let cache = ThreadCache::new_in(&stats);
+        let a = &cache;
+        let l = Layout::new::<u64>();
+        let mut ptrs = [ptr::null_mut(); 4 * TCACHE_LIMIT];
+        unsafe {
+            for p in ptrs.iter_mut() { *p = a.alloc(l) }
+            for p in ptrs.iter() { a.dealloc(*p, l) }
+        }
+        assert!(cache.cached_blocks() <= TCACHE_LIMIT);
+        assert!(stats.stats().deallocations > 0);
+        cache.flush();
+        assert!(cache.cached_blocks() == 0);
+        assert!(stats.stats().live_bytes == 0);
+    }
+
+    #[test]
+    fn test_large_and_aligned() {
+        let cache = ThreadCache::new();
+        let a = &cache;
+        unsafe {
+            let l = Layout::from_size_align(64, 64).unwrap();
+            let p = a.alloc(l);
+            assert!((p as usize) & 63 == 0);
+            a.dealloc(p, l);
+
+            let p = a.alloc(Layout::from_size_align(16, 8).unwrap());
+            *p = 9;
+            let p = a.realloc(p, Layout::from_size_align(16, 8).unwrap(), 4 * TCACHE_MAX_SIZE);
+            assert!(*p == 9);
+            a.dealloc(p, Layout::from_size_align(4 * TCACHE_MAX_SIZE, 8).unwrap());
+        }
+        assert!(cache.cached_blocks() <= TCACHE_BATCH);
+    }
+
+    static CACHE : ThreadCache = ThreadCache::new();
+
+    #[test]
+    fn test_threads() {
+        let handles: std::vec::Vec<_> = (0..8).map(|t| std::thread::spawn(move || {
+            for i in 0..1000usize {
+                let shared = Arc::new_in(i * t, &CACHE);
+                let mut v = Vec::new_in(&CACHE);
+                for j in 0..64 {
+                    v.push(j + *shared.as_ref());
+                }
+                assert!(v[63] == 63 + i * t);
+            }
+        })).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+//
+// Two-Level Segregated Fit allocator (M. Masmano et al.), O(1) alloc and free
+// over memory regions handed in by the caller. It only depends on core.
+//
+use core::*;
+use core::alloc::*;
+use crate::spin::*;
+use crate::UsableSize;
+
+const ALIGN_LOG2    : usize = 4;
+const ALIGN         : usize = 1 << ALIGN_LOG2;
+const SL_LOG2       : usize = 4;
+const SL_COUNT      : usize = 1 << SL_LOG2;
+const FL_SHIFT      : usize = SL_LOG2 + ALIGN_LOG2;
+const SMALL_SIZE    : usize = 1 << FL_SHIFT;
+const FL_COUNT      : usize = usize::BITS as usize - FL_SHIFT + 1;
+
+const HEADER        : usize = 2 * mem::size_of::<usize>();
+const MIN_BLOCK     : usize = HEADER + ALIGN;
+const FREE          : usize = 1;
+
+// the free list links live in the payload, used blocks only carry the first two fields
+#[repr(C)]
+struct Block {
+    prev_phys   : *mut Block,
+    size        : usize,
+    next_free   : *mut Block,
+    prev_free   : *mut Block,
+}
+
+impl Block {
+    #[inline] unsafe fn size(b: *mut Block) -> usize { (*b).size & !FREE }
+    #[inline] unsafe fn is_free(b: *mut Block) -> bool { (*b).size & FREE != 0 }
+    #[inline] unsafe fn set_size(b: *mut Block, size: usize) { (*b).size = size | ((*b).size & FREE) }
+    #[inline] unsafe fn set_free(b: *mut Block, free: bool) { (*b).size = Self::size(b) | if free { FREE } else { 0 } }
+    #[inline] unsafe fn payload(b: *mut Block) -> *mut u8 { (b as *mut u8).add(HEADER) }
+    #[inline] unsafe fn from_payload(p: *mut u8) -> *mut Block { p.sub(HEADER) as *mut Block }
+    #[inline] unsafe fn next_phys(b: *mut Block) -> *mut Block { Self::payload(b).add(Self::size(b)) as *mut Block }
+}
+
+#[inline]
+fn fls(x: usize) -> usize { usize::BITS as usize - 1 - x.leading_zeros() as usize }
+
+#[inline]
+fn align_up(x: usize, align: usize) -> usize { (x + align - 1) & !(align - 1) }
+
+fn mapping_insert(size: usize) -> (usize, usize) {
+    if size < SMALL_SIZE {
+        (0, size / (SMALL_SIZE / SL_COUNT))
+    } else {
+        let f = fls(size);
+        (f - FL_SHIFT + 1, (size >> (f - SL_LOG2)) ^ SL_COUNT)
+    }
+}
+
+// round up so that any block of the returned class is large enough
+fn mapping_search(size: usize) -> Option<(usize, usize)> {
+    let size = if size >= SMALL_SIZE { size.checked_add((1 << (fls(size) - SL_LOG2)) - 1)? } else { size };
+    let (fl, sl) = mapping_insert(size);
+    if fl < FL_COUNT { Some((fl, sl)) } else { None }
+}
+
+struct Control {
+    fl_bitmap   : usize,
+    sl_bitmap   : [usize; FL_COUNT],
+    blocks      : [[*mut Block; SL_COUNT]; FL_COUNT],
+}
+
+unsafe impl Send for Control {}
+
+impl Control {
+    const fn new() -> Self {
+        Self {
+            fl_bitmap   : 0,
+            sl_bitmap   : [0; FL_COUNT],
+            blocks      : [[ptr::null_mut(); SL_COUNT]; FL_COUNT],
+        }
+    }
+
+    unsafe fn insert_free(&mut self, b: *mut Block) {
+        let (fl, sl) = mapping_insert(Block::size(b));
+        let head = self.blocks[fl][sl];
+        (*b).next_free = head;
+        (*b).prev_free = ptr::null_mut();
+        if !head.is_null() { (*head).prev_free = b }
+        self.blocks[fl][sl] = b;
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+        Block::set_free(b, true);
+    }
+
+    unsafe fn remove_free(&mut self, b: *mut Block) {
+        let (fl, sl) = mapping_insert(Block::size(b));
+        let next = (*b).next_free;
+        let prev = (*b).prev_free;
+        if !next.is_null() { (*next).prev_free = prev }
+        if !prev.is_null() { (*prev).next_free = next }
+
+        if self.blocks[fl][sl] == b {
+            self.blocks[fl][sl] = next;
+            if next.is_null() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+        Block::set_free(b, false);
+    }
+
+    fn find_suitable(&self, fl: usize, sl: usize) -> *mut Block {
+        let mut fl = fl;
+        let mut sl_map = self.sl_bitmap[fl] & (!0usize << sl);
+        if sl_map == 0 {
+            let fl_map = if fl + 1 < usize::BITS as usize { self.fl_bitmap & (!0usize << (fl + 1)) } else { 0 };
+            if fl_map == 0 { return ptr::null_mut() }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+        }
+        self.blocks[fl][sl_map.trailing_zeros() as usize]
+    }
+
+    // merge `b` with its physical neighbours if they are free, `b` must not be in a list
+    unsafe fn merge(&mut self, mut b: *mut Block) -> *mut Block {
+        let prev = (*b).prev_phys;
+        if !prev.is_null() && Block::is_free(prev) {
+            self.remove_free(prev);
+            Block::set_size(prev, Block::size(prev) + HEADER + Block::size(b));
+            (*Block::next_phys(prev)).prev_phys = prev;
+            b = prev;
+        }
+
+        let next = Block::next_phys(b);
+        if Block::is_free(next) {
+            self.remove_free(next);
+            Block::set_size(b, Block::size(b) + HEADER + Block::size(next));
+            (*Block::next_phys(b)).prev_phys = b;
+        }
+        b
+    }
+
+    // give the tail of a used block beyond `size` back to the free lists
+    unsafe fn trim(&mut self, b: *mut Block, size: usize) {
+        if Block::size(b) < size + MIN_BLOCK { return }
+
+        let rest = Block::payload(b).add(size) as *mut Block;
+        (*rest).prev_phys = b;
+        (*rest).size = Block::size(b) - size - HEADER;
+        (*Block::next_phys(rest)).prev_phys = rest;
+        Block::set_size(b, size);
+
+        let rest = self.merge(rest);
+        self.insert_free(rest);
+    }
+
+    unsafe fn add_region(&mut self, mem: &'static mut [u8]) {
+        let start   = align_up(mem.as_mut_ptr() as usize, ALIGN);
+        let end     = (mem.as_mut_ptr() as usize + mem.len()) & !(ALIGN - 1);
+        if end < start || end - start < 2 * HEADER + ALIGN { return }
+
+        let b = start as *mut Block;
+        (*b).prev_phys = ptr::null_mut();
+        (*b).size = end - start - 2 * HEADER;
+
+        // zero sized sentinel, never free, stops merging past the end of the region
+        let sentinel = Block::next_phys(b);
+        (*sentinel).prev_phys = b;
+        (*sentinel).size = 0;
+
+        self.insert_free(b);
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let size = match layout.size().checked_add(ALIGN - 1) {
+            Some(s) => cmp::max(s & !(ALIGN - 1), ALIGN),
+            None => return ptr::null_mut()
+        };
+        let align = layout.align();
+        let search = if align <= ALIGN { Some(size) } else { size.checked_add(align + MIN_BLOCK) };
+
+        let b = match search.and_then(mapping_search) {
+            Some((fl, sl)) => self.find_suitable(fl, sl),
+            None => return ptr::null_mut()
+        };
+        if b.is_null() { return ptr::null_mut() }
+        self.remove_free(b);
+
+        let mut b = b;
+        if align > ALIGN {
+            let payload = Block::payload(b) as usize;
+            let mut aligned = align_up(payload, align);
+            if aligned != payload && aligned - payload < MIN_BLOCK {
+                aligned = align_up(payload + MIN_BLOCK, align);
+            }
+
+            let gap = aligned - payload;
+            if gap != 0 {
+                // split the misaligned front off as a free block
+                let nb = (aligned - HEADER) as *mut Block;
+                (*nb).prev_phys = b;
+                (*nb).size = Block::size(b) - gap;
+                (*Block::next_phys(nb)).prev_phys = nb;
+                (*b).size = gap - HEADER;
+                self.insert_free(b);
+                b = nb;
+            }
+        }
+
+        self.trim(b, size);
+        Block::payload(b)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let b = self.merge(Block::from_payload(ptr));
+        self.insert_free(b);
+    }
+
+    // resize in place when possible, null otherwise
+    unsafe fn resize(&mut self, ptr: *mut u8, new_size: usize) -> *mut u8 {
+        let size = match new_size.checked_add(ALIGN - 1) {
+            Some(s) => cmp::max(s & !(ALIGN - 1), ALIGN),
+            None => return ptr::null_mut()
+        };
+        let b = Block::from_payload(ptr);
+
+        if Block::size(b) < size {
+            let next = Block::next_phys(b);
+            if !Block::is_free(next) || Block::size(b) + HEADER + Block::size(next) < size {
+                return ptr::null_mut()
+            }
+            self.remove_free(next);
+            Block::set_size(b, Block::size(b) + HEADER + Block::size(next));
+            (*Block::next_phys(b)).prev_phys = b;
+        }
+
+        self.trim(b, size);
+        ptr
+    }
+}
+
+///
+/// TLSF allocator over caller provided memory. It can be declared as a static
+/// with `Tlsf::new()` and fed regions later with `add_region`.
+///
+pub struct Tlsf {
+    control : SpinLock<Control>,
+}
+
+impl Tlsf {
+    pub const fn new() -> Self { Self { control: SpinLock::new(Control::new()) } }
+
+    pub fn with_region(mem: &'static mut [u8]) -> Self {
+        let t = Self::new();
+        t.add_region(mem);
+        t
+    }
+
+    pub fn add_region(&self, mem: &'static mut [u8]) {
+        unsafe { self.control.lock().add_region(mem) }
+    }
+
+    // smallest region `add_region` turns into a free block that can serve `layout`,
+    // the search rounds sizes up to the next class so the request alone is not enough
+    pub fn region_size(layout: &Layout) -> Option<usize> {
+        let size = cmp::max(layout.size().checked_add(ALIGN - 1)? & !(ALIGN - 1), ALIGN);
+        let search = if layout.align() <= ALIGN { size } else { size.checked_add(layout.align() + MIN_BLOCK)? };
+        let class = if search >= SMALL_SIZE {
+            let s = search.checked_add((1 << (fls(search) - SL_LOG2)) - 1)?;
+            s & !((1 << (fls(s) - SL_LOG2)) - 1)
+        } else {
+            search
+        };
+        class.checked_add(2 * HEADER + ALIGN)
+    }
+}
+
+impl Default for Tlsf {
+    fn default() -> Self { Self::new() }
+}
+
+unsafe impl GlobalAlloc for Tlsf {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.control.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        self.control.lock().dealloc(ptr)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = self.control.lock().resize(ptr, new_size);
+        if !p.is_null() { return p }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+unsafe impl GlobalAlloc for &Tlsf {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+// a used block only changes size through its owner
+unsafe impl UsableSize for Tlsf {
+    unsafe fn usable_size(&self, ptr: *mut u8, _layout: Layout) -> usize { Block::size(Block::from_payload(ptr)) }
+}
+
+unsafe impl UsableSize for &Tlsf {
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize { (*self).usable_size(ptr, layout) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    extern crate std;
+
+    fn region(size: usize) -> &'static mut [u8] {
+        std::boxed::Box::leak(std::vec![0u8; size].into_boxed_slice())
+    }
+
+    static mut BUFFER : [u8; 64 * 1024] = [0; 64 * 1024];
+
+    #[test]
+    fn test_static_buffer() {
+        let tlsf = Tlsf::with_region(unsafe { &mut *ptr::addr_of_mut!(BUFFER) });
+        let mut v = Vec::new_in(&tlsf);
+        for i in 0..1000 {
+            v.push(i);
+        }
+        let mut hm = HashMap::new_in(&tlsf);
+        for i in 0..100 {
+            hm.set(i, String::from_in("value", &tlsf));
+        }
+        assert!(v[999] == 999);
+        assert!(*hm.get(42).unwrap() == "value");
+    }
+
+    #[test]
+    fn test_coalescing() {
+        let tlsf = Tlsf::with_region(region(4096));
+        let a = &tlsf;
+        let l = Layout::from_size_align(256, 8).unwrap();
+        let mut ptrs = std::vec::Vec::new();
+        loop {
+            let p = unsafe { a.alloc(l) };
+            if p.is_null() { break }
+            ptrs.push(p);
+        }
+        assert!(ptrs.len() > 8);
+
+        // free every other block, then the rest: everything merges back
+        for p in ptrs.iter().step_by(2) {
+            unsafe { a.dealloc(*p, l) };
+        }
+        for p in ptrs.iter().skip(1).step_by(2) {
+            unsafe { a.dealloc(*p, l) };
+        }
+        let big = unsafe { a.alloc(Layout::from_size_align(3 * 1024, 8).unwrap()) };
+        assert!(!big.is_null());
+    }
+
+    #[test]
+    fn test_alignment() {
+        let tlsf = Tlsf::with_region(region(64 * 1024));
+        let a = &tlsf;
+        for i in 0..12 {
+            let align = 1 << i;
+            let l = Layout::from_size_align(24, align).unwrap();
+            let p = unsafe { a.alloc(l) };
+            assert!(!p.is_null());
+            assert!((p as usize) & (align - 1) == 0);
+            unsafe { ptr::write_bytes(p, 0xff, 24) };
+        }
+    }
+
+    #[test]
+    fn test_realloc() {
+        let tlsf = Tlsf::with_region(region(4096));
+        let a = &tlsf;
+        let l = Layout::from_size_align(32, 8).unwrap();
+        unsafe {
+            let p = a.alloc(l);
+            for i in 0..32 { *p.add(i) = i as u8 }
+            let q = a.realloc(p, l, 512);
+            assert!(p == q);
+            let r = a.realloc(q, Layout::from_size_align(512, 8).unwrap(), 16);
+            assert!(r == q);
+            for i in 0..16 { assert!(*r.add(i) == i as u8) }
+        }
+    }
+
+    #[test]
+    fn test_region_size() {
+        for &(size, align) in &[(1, 1), (100, 8), (70000, 1), (70000, 4096), (4 << 20, 8192), (5000, 1 << 16)] {
+            let l = Layout::from_size_align(size, align).unwrap();
+            let tlsf = Tlsf::with_region(region(Tlsf::region_size(&l).unwrap()));
+            let p = unsafe { tlsf.alloc(l) };
+            assert!(!p.is_null() && (p as usize) & (align - 1) == 0);
+        }
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        let tlsf = Tlsf::with_region(region(1024));
+        let a = &tlsf;
+        assert!(unsafe { a.alloc(Layout::from_size_align(2048, 8).unwrap()) }.is_null());
+        assert!(!unsafe { a.alloc(Layout::from_size_align(512, 8).unwrap()) }.is_null());
+    }
+}
@@ -0,0 +1,457 @@
+//
+// Copyright 2020-Present (c) Raja Lehtihet & Wael El Oraiby
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+//
+
+
+use core::*;
+use core::alloc::*;
+use crate::*;
+use crate::spin::*;
+
+//
+// Trace format: one tag byte per event followed by LEB128 encoded fields
+//
+//   TRACE_ALLOC / TRACE_ALLOC_ZEROED    id, size, log2(align) (one byte)
+//   TRACE_FREE                          id
+//   TRACE_REALLOC                       id, new size
+//
+// Ids are handed out in allocation order starting from 0, a block keeps its id
+// through reallocations. Failed requests are not recorded.
+//
+pub const TRACE_ALLOC           : u8 = 1;
+pub const TRACE_ALLOC_ZEROED    : u8 = 2;
+pub const TRACE_FREE            : u8 = 3;
+pub const TRACE_REALLOC         : u8 = 4;
+
+pub trait TraceSink {
+    fn write(&mut self, bytes: &[u8]);
+}
+
+impl<A: UsableSize> TraceSink for Vec<u8, A> {
+    fn write(&mut self, bytes: &[u8]) { self.append(bytes) }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    Alloc { id: usize, layout: Layout, zeroed: bool },
+    Free { id: usize },
+    Realloc { id: usize, new_size: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceError {
+    Truncated,
+    BadTag(u8),
+    BadLayout,
+    UnknownId(usize),
+    IdOutOfOrder(usize),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Truncated => write!(f, "truncated trace"),
+            TraceError::BadTag(t) => write!(f, "unknown event tag {}", t),
+            TraceError::BadLayout => write!(f, "invalid layout"),
+            TraceError::UnknownId(id) => write!(f, "unknown block id {}", id),
+            TraceError::IdOutOfOrder(id) => write!(f, "block id {} allocated out of order", id),
+        }
+    }
+}
+
+fn put_varint(buf: &mut [u8; 24], len: &mut usize, mut x: usize) {
+    loop {
+        let b = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            buf[*len] = b;
+            *len += 1;
+            return
+        }
+        buf[*len] = b | 0x80;
+        *len += 1;
+    }
+}
+
+///
+/// Decodes a recorded trace event by event.
+///
+pub struct TraceReader<'a> {
+    data    : &'a [u8],
+}
+
+impl<'a> TraceReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self { Self { data } }
+
+    fn byte(&mut self) -> Result<u8, TraceError> {
+        let (&b, rest) = self.data.split_first().ok_or(TraceError::Truncated)?;
+        self.data = rest;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<usize, TraceError> {
+        let mut x = 0usize;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= usize::BITS { return Err(TraceError::Truncated) }
+            x |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 { return Ok(x) }
+            shift += 7;
+        }
+    }
+
+    fn event(&mut self) -> Result<TraceEvent, TraceError> {
+        match self.byte()? {
+            tag @ (TRACE_ALLOC | TRACE_ALLOC_ZEROED) => {
+                let id = self.varint()?;
+                let size = self.varint()?;
+                let align = 1usize.checked_shl(self.byte()? as u32).ok_or(TraceError::BadLayout)?;
+                let layout = Layout::from_size_align(size, align).map_err(|_| TraceError::BadLayout)?;
+                Ok(TraceEvent::Alloc { id, layout, zeroed: tag == TRACE_ALLOC_ZEROED })
+            },
+            TRACE_FREE => Ok(TraceEvent::Free { id: self.varint()? }),
+            TRACE_REALLOC => {
+                let id = self.varint()?;
+                Ok(TraceEvent::Realloc { id, new_size: self.varint()? })
+            },
+            tag => Err(TraceError::BadTag(tag)),
+        }
+    }
+}
+
+impl Iterator for TraceReader<'_> {
+    type Item = Result<TraceEvent, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() { return None }
+        let e = self.event();
+        if e.is_err() { self.data = &[] }
+        Some(e)
+    }
+}
+
+struct Recorder<S: TraceSink, A: GlobalAlloc> {
+    sink    : S,
+    ids     : Option<HashMap<usize, usize, A>>,
+    next_id : usize,
+    dropped : usize,
+}
+
+unsafe impl<S: TraceSink + Send, A: GlobalAlloc + Send> Send for Recorder<S, A> {}
+
+impl<S: TraceSink, A: GlobalAlloc> Recorder<S, A> {
+    fn emit(&mut self, tag: u8, fields: &[usize], align: Option<usize>) {
+        let mut buf = [0u8; 24];
+        let mut len = 1;
+        buf[0] = tag;
+        for &f in fields {
+            put_varint(&mut buf, &mut len, f);
+        }
+        if let Some(a) = align {
+            buf[len] = a.trailing_zeros() as u8;
+            len += 1;
+        }
+        self.sink.write(&buf[..len]);
+    }
+}
+
+///
+/// Wrapper writing every request made to the inner allocator to a `TraceSink`, the
+/// trace can be replayed later against another allocator with `replay`. The sink must
+/// not allocate from the recorder itself.
+///
+pub struct RecordAlloc<S: TraceSink, A: UsableSize + Clone = System> {
+    inner   : A,
+    rec     : SpinLock<Recorder<S, A>>,
+}
+
+impl<S: TraceSink> RecordAlloc<S> {
+    pub const fn new(sink: S) -> Self { Self::new_in(sink, System) }
+}
+
+impl<S: TraceSink, A: UsableSize + Clone> RecordAlloc<S, A> {
+    pub const fn new_in(sink: S, inner: A) -> Self {
+        Self { inner, rec: SpinLock::new(Recorder { sink, ids: None, next_id: 0, dropped: 0 }) }
+    }
+
+    pub fn with_sink<R, F: FnOnce(&mut S) -> R>(&self, f: F) -> R {
+        f(&mut self.rec.lock().sink)
+    }
+
+    pub fn into_sink(self) -> S { self.rec.into_inner().sink }
+
+    // blocks left out of the trace because the id map couldn't grow, a block whose new
+    // address couldn't be stored on realloc is missing its later events
+    pub fn dropped(&self) -> usize { self.rec.lock().dropped }
+
+    // a block the id map can't hold is left out of the trace, the allocation succeeded
+    // and the recorder must not abort it
+    fn record_alloc(&self, ptr: *mut u8, layout: &Layout, zeroed: bool) {
+        let mut r = self.rec.lock();
+        let id = r.next_id;
+        let inner = &self.inner;
+        if r.ids.get_or_insert_with(|| HashMap::new_in(inner.clone())).try_set(ptr as usize, id).is_err() {
+            r.dropped += 1;
+            return
+        }
+        r.next_id += 1;
+        let tag = if zeroed { TRACE_ALLOC_ZEROED } else { TRACE_ALLOC };
+        r.emit(tag, &[id, layout.size()], Some(layout.align()));
+    }
+}
+
+unsafe impl<S: TraceSink, A: UsableSize + Clone> GlobalAlloc for RecordAlloc<S, A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = self.inner.alloc(layout);
+        if !p.is_null() { self.record_alloc(p, &layout, false) }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = self.inner.alloc_zeroed(layout);
+        if !p.is_null() { self.record_alloc(p, &layout, true) }
+        p
+    }
+
+    // recorded before the block goes back, its address may be reused right away
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        {
+            let mut r = self.rec.lock();
+            let id = r.ids.as_ref().and_then(|m| m.get(ptr as usize)).copied();
+            if let Some(id) = id {
+                r.ids.as_mut().unwrap().remove(ptr as usize);
+                r.emit(TRACE_FREE, &[id], None);
+            }
+        }
+        self.inner.dealloc(ptr, layout)
+    }
+
+    // done under the lock so no other thread can record the old address in between
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let mut r = self.rec.lock();
+        let p = self.inner.realloc(ptr, layout, new_size);
+        if !p.is_null() {
+            let id = r.ids.as_ref().and_then(|m| m.get(ptr as usize)).copied();
+            if let Some(id) = id {
+                let m = r.ids.as_mut().unwrap();
+                m.remove(ptr as usize);
+                if m.try_set(p as usize, id).is_err() { r.dropped += 1 }
+                r.emit(TRACE_REALLOC, &[id, new_size], None);
+            }
+        }
+        p
+    }
+}
+
+unsafe impl<S: TraceSink, A: UsableSize + Clone> GlobalAlloc for &RecordAlloc<S, A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { (*self).alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { (*self).alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { (*self).dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { (*self).realloc(ptr, layout, new_size) }
+}
+
+unsafe impl<S: TraceSink, A: UsableSize + Clone> UsableSize for RecordAlloc<S, A> {}
+unsafe impl<S: TraceSink, A: UsableSize + Clone> UsableSize for &RecordAlloc<S, A> {}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub events      : usize,
+    pub failures    : usize,    // requests the allocator under test returned null for
+    pub nanos       : u64,
+    pub peak_bytes  : usize,    // peak of the requested live bytes
+    pub leftover    : usize,    // blocks still live at the end of the trace, freed after timing
+}
+
+///
+/// Run a recorded trace against `alloc`. The replay keeps its own bookkeeping in
+/// `System` so only the requests of the trace reach the allocator under test.
+///
+pub fn replay<A: GlobalAlloc>(trace: &[u8], alloc: &A) -> Result<ReplayReport, TraceError> {
+    // validate up front so the timed loop can't stop halfway and leak its blocks: ids
+    // come in allocation order, frees and reallocs name a live block and every new
+    // size makes a valid layout with the block's alignment
+    let mut events = Vec::new();
+    let mut aligns : Vec<usize> = Vec::new();     // 0 once the block is freed
+    for e in TraceReader::new(trace) {
+        let e = e?;
+        match e {
+            TraceEvent::Alloc { id, layout, .. } => {
+                if id != aligns.len() { return Err(TraceError::IdOutOfOrder(id)) }
+                aligns.push(layout.align());
+            },
+            TraceEvent::Free { id } => {
+                match aligns.as_mut_slice().get_mut(id) {
+                    Some(a) if *a != 0 => *a = 0,
+                    _ => return Err(TraceError::UnknownId(id)),
+                }
+            },
+            TraceEvent::Realloc { id, new_size } => {
+                match aligns.as_slice().get(id) {
+                    Some(&a) if a != 0 => if Layout::from_size_align(new_size, a).is_err() { return Err(TraceError::BadLayout) },
+                    _ => return Err(TraceError::UnknownId(id)),
+                }
+            },
+        }
+        events.push(e);
+    }
+
+    let mut blocks : Vec<(*mut u8, Layout)> = Vec::new();
+    let mut report = ReplayReport::default();
+    let mut live = 0usize;
+    let start = crate::os::now_nanos();
+    for e in events.iter() {
+        report.events += 1;
+        match *e {
+            TraceEvent::Alloc { layout, zeroed, .. } => {
+                let p = unsafe { if zeroed { alloc.alloc_zeroed(layout) } else { alloc.alloc(layout) } };
+                blocks.push((p, layout));
+                if p.is_null() { report.failures += 1; continue }
+                live += layout.size();
+            },
+            TraceEvent::Free { id } => {
+                let (p, layout) = blocks[id];
+                if p.is_null() { continue }
+                unsafe { alloc.dealloc(p, layout) };
+                blocks[id].0 = ptr::null_mut();
+                live -= layout.size();
+            },
+            TraceEvent::Realloc { id, new_size } => {
+                let (p, layout) = blocks[id];
+                if p.is_null() { continue }
+                let q = unsafe { alloc.realloc(p, layout, new_size) };
+                if q.is_null() { report.failures += 1; continue }
+                blocks[id] = (q, unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) });
+                live = live - layout.size() + new_size;
+            },
+        }
+        report.peak_bytes = cmp::max(report.peak_bytes, live);
+    }
+    report.nanos = crate::os::now_nanos() - start;
+
+    for &(p, layout) in blocks.iter() {
+        if !p.is_null() {
+            report.leftover += 1;
+            unsafe { alloc.dealloc(p, layout) };
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let rec = RecordAlloc::new(Vec::<u8>::new());
+        {
+            let mut v = Vec::new_in(&rec);
+            for i in 0..20u64 {
+                v.push(i);
+            }
+            let _b = Box::new_in(1u8, &rec);
+        }
+        let trace = rec.into_sink();
+        let events: Vec<TraceEvent> = TraceReader::new(trace.as_slice()).map(|e| e.unwrap()).collect();
+        assert!(events[0] == TraceEvent::Alloc { id: 0, layout: Layout::array::<u64>(16).unwrap(), zeroed: false });
+        assert!(events[1] == TraceEvent::Realloc { id: 0, new_size: 256 });
+        assert!(events[2] == TraceEvent::Alloc { id: 1, layout: Layout::new::<u8>(), zeroed: false });
+        assert!(events[3] == TraceEvent::Free { id: 1 });
+        assert!(events[4] == TraceEvent::Free { id: 0 });
+        assert!(events.len() == 5);
+        assert!(trace.len() == 5 + 4 + 4 + 2 + 2);
+    }
+
+    #[test]
+    fn test_ids_out_of_memory() {
+        // the first block is allocated, then the id map fails to grow
+        let fail = FailAlloc::new(FailPolicy::Nth(2));
+        let rec = RecordAlloc::new_in(Vec::<u8>::new(), &fail);
+        {
+            let _a = Box::new_in(1u8, &rec);
+            let _b = Box::new_in(2u8, &rec);
+        }
+        assert!(rec.dropped() == 1);
+        let trace = rec.into_sink();
+        let events: Vec<TraceEvent> = TraceReader::new(trace.as_slice()).map(|e| e.unwrap()).collect();
+        assert!(events[0] == TraceEvent::Alloc { id: 0, layout: Layout::new::<u8>(), zeroed: false });
+        assert!(events[1] == TraceEvent::Free { id: 0 });
+        assert!(events.len() == 2);
+        assert!(replay(trace.as_slice(), &System).is_ok());
+    }
+
+    #[test]
+    fn test_replay() {
+        let rec = RecordAlloc::new(Vec::<u8>::new());
+        {
+            let mut hm = HashMap::new_in(&rec);
+            for i in 0..100 {
+                hm.set(i, String::from_in("value", &rec));
+            }
+            for i in 0..50 {
+                hm.remove(i);
+            }
+            core::mem::forget(Box::new_in([0u8; 1000], &rec));
+        }
+        let trace = rec.into_sink();
+
+        let stats = StatsAlloc::new();
+        let report = replay(trace.as_slice(), &stats).unwrap();
+        assert!(report.events == TraceReader::new(trace.as_slice()).count());
+        assert!(report.failures == 0);
+        assert!(report.leftover == 1);
+        assert!(report.peak_bytes == stats.stats().peak_bytes);
+        assert!(stats.stats().live_bytes == 0);
+
+        let tight = StackAlloc::with_capacity(4096);
+        let report = replay(trace.as_slice(), &&tight).unwrap();
+        assert!(report.failures > 0);
+    }
+
+    #[test]
+    fn test_bad_trace() {
+        assert!(replay(&[9], &System) == Err(TraceError::BadTag(9)));
+        assert!(replay(&[TRACE_ALLOC, 0, 0x80], &System) == Err(TraceError::Truncated));
+        assert!(replay(&[TRACE_FREE, 3], &System) == Err(TraceError::UnknownId(3)));
+
+        // rejected before anything reaches the allocator
+        let stats = StatsAlloc::new();
+        let huge_id = [TRACE_ALLOC, 0xff, 0xff, 0xff, 0xff, 0x0f, 8, 3];
+        assert!(replay(&huge_id, &stats) == Err(TraceError::IdOutOfOrder(0xffff_ffff)));
+        let double_free = [TRACE_ALLOC, 0, 8, 3, TRACE_FREE, 0, TRACE_FREE, 0];
+        assert!(replay(&double_free, &stats) == Err(TraceError::UnknownId(0)));
+        let mut bad_size = [0u8; 24];
+        bad_size[..6].copy_from_slice(&[TRACE_ALLOC, 0, 8, 3, TRACE_REALLOC, 0]);
+        let mut len = 6;
+        put_varint(&mut bad_size, &mut len, usize::MAX);
+        assert!(replay(&bad_size[..len], &stats) == Err(TraceError::BadLayout));
+        assert!(stats.stats().allocations == 0);
+    }
+}
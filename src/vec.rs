@@ -29,35 +29,51 @@
 //
 
 use core::*;
+use core::alloc::GlobalAlloc;
 use core::ops::*;
 use core::slice::*;
 use crate::*;
 
 
 #[repr(C)]
-pub struct Vec<T> {
+pub struct Vec<T, A: GlobalAlloc = System> {
     elements    : *mut T,
     count       : usize,
     capacity    : usize,
+    alloc       : A,
 }
 
 impl<T> Vec<T> {
+    #[track_caller]
     pub fn with_capacity(c: usize) -> Self {
-        if c == 0 { Self::new() }
-        else {
-            Self {
-                elements: unsafe { alloc_array(c) },
-                count   : 0,
-                capacity: c,
-            }
-        }
+        Self::with_capacity_in(c, System)
     }
 
     pub fn new() -> Self {
+        Self::new_in(System)
+    }
+
+    pub fn from_raw_parts(ptr: *mut T, len: usize, cap: usize) -> Self {
+        Self::from_raw_parts_in(ptr, len, cap, System)
+    }
+}
+
+// large tables get huge pages to cut down on TLB misses
+#[cfg(all(unix, feature = "libc"))]
+impl<T> Vec<T, HugePageAlloc> {
+    #[track_caller]
+    pub fn with_capacity_huge(c: usize) -> Self {
+        Self::with_capacity_in(c, HugePageAlloc::new())
+    }
+}
+
+impl<T, A: GlobalAlloc> Vec<T, A> {
+    pub fn new_in(alloc: A) -> Self {
         Self {
-            elements: ptr::null_mut(),
+            elements: ptr::NonNull::dangling().as_ptr(),
             count   : 0,
             capacity: 0,
+            alloc,
         }
     }
 
@@ -69,25 +85,6 @@ impl<T> Vec<T> {
 
     pub fn len(&self) -> usize { self.count }
 
-    pub fn push(&mut self, t: T) {
-        if self.count >= self.capacity {
-            let new_size    = if self.capacity == 0 { 16 } else { self.capacity * 2 };
-            let new_ptr     = unsafe { alloc_array::<T>(new_size) };
-            let old_ptr     = self.elements;
-
-            for i in 0..self.count {
-                let v = unsafe { old_ptr.offset(i as isize).read() };    // v = old[i];
-                unsafe { new_ptr.offset(i as isize).write(v) };          // new[i] = v;
-            }
-            unsafe { free_array_ptr(self.elements, self.capacity) };
-            self.elements   = new_ptr;
-            self.capacity   = new_size;
-        }
-
-        unsafe { self.elements.offset(self.count as isize).write(t) };
-        self.count += 1
-    }
-
     pub fn pop(&mut self) -> Option<T> {
         if self.count == 0 { None }
         else {
@@ -111,15 +108,12 @@ impl<T> Vec<T> {
     }
 
     fn drop_elements(&mut self) {
-        let arr      = unsafe { core::slice::from_raw_parts_mut(self.elements, self.count) };
-        for i in 0..self.count {
-            unsafe { ptr::drop_in_place(&arr[i] as *const T as *mut T) };
-        }
+        unsafe { ptr::drop_in_place(self.as_mut_slice()) };
     }
 
     pub fn to_iter<'a>(&self) -> ::core::slice::Iter<'a, T> {
         let arr      = unsafe { core::slice::from_raw_parts(self.elements, self.count) };
-        arr.into_iter()
+        arr.iter()
     }
 
     pub fn last(&self) -> Option<&T> {
@@ -132,20 +126,78 @@ impl<T> Vec<T> {
 
     pub fn capacity(&self) -> usize { self.capacity }
 
-    pub fn iter(&self) -> slice::Iter<T> {
-        self.as_slice().into_iter()
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.as_slice().iter()
     }
 
-    pub fn iter_mut(&mut self) -> slice::IterMut<T> {
-        self.as_mut_slice().into_iter()
+    pub fn iter_mut(&mut self) -> slice::IterMut<'_, T> {
+        self.as_mut_slice().iter_mut()
     }
 
-    pub fn from_raw_parts(ptr: *mut T, len: usize, cap: usize) -> Self {
-        Self { elements: ptr, count: len, capacity: cap }
+    pub fn allocator(&self) -> &A { &self.alloc }
+
+    pub fn from_raw_parts_in(ptr: *mut T, len: usize, cap: usize, alloc: A) -> Self {
+        Self { elements: ptr, count: len, capacity: cap, alloc }
+    }
+}
+
+impl<T, A: UsableSize> Vec<T, A> {
+    #[track_caller]
+    pub fn with_capacity_in(c: usize, alloc: A) -> Self {
+        let mut v = Self::new_in(alloc);
+        v.reserve(c);
+        v
+    }
+
+    // make room for at least `additional` more elements, on failure the vector is left untouched
+    #[track_caller]
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        let needed = self.count.checked_add(additional).ok_or(AllocError::CapacityOverflow)?;
+        if needed <= self.capacity { return Ok(()) }
+
+        let new_size    = if self.capacity == 0 { needed } else { cmp::max(needed, self.capacity * 2) };
+        let new_ptr     = if self.capacity == 0 {
+            unsafe { try_alloc_array_in::<T, A>(&self.alloc, new_size)? }
+        } else {
+            unsafe { try_realloc_array_in(&self.alloc, self.elements, self.capacity, new_size)? }
+        };
+
+        // keep whatever the allocator gave on top of what was asked
+        self.elements   = new_ptr;
+        self.capacity   = unsafe { usable_count_in(&self.alloc, new_ptr, new_size) };
+        Ok(())
+    }
+
+    #[track_caller]
+    pub fn reserve(&mut self, additional: usize) {
+        if let Err(e) = self.try_reserve(additional) {
+            handle_alloc_error(e)
+        }
+    }
+
+    // hands the element back on failure so the caller can retry or keep it
+    #[track_caller]
+    pub fn try_push(&mut self, t: T) -> Result<(), (T, AllocError)> {
+        if self.count >= self.capacity {
+            if let Err(e) = self.try_reserve(if self.capacity == 0 { 16 } else { 1 }) {
+                return Err((t, e))
+            }
+        }
+
+        unsafe { self.elements.add(self.count).write(t) };
+        self.count += 1;
+        Ok(())
+    }
+
+    #[track_caller]
+    pub fn push(&mut self, t: T) {
+        if let Err((_, e)) = self.try_push(t) {
+            handle_alloc_error(e)
+        }
     }
 }
 
-impl<'a, T> IntoIterator for &'a Vec<T> {
+impl<'a, T, A: GlobalAlloc> IntoIterator for &'a Vec<T, A> {
     type Item = &'a T;
     type IntoIter = slice::Iter<'a, T>;
 
@@ -154,7 +206,7 @@ impl<'a, T> IntoIterator for &'a Vec<T> {
     }
 }
 
-impl<'a, T> IntoIterator for &'a mut Vec<T> {
+impl<'a, T, A: GlobalAlloc> IntoIterator for &'a mut Vec<T, A> {
     type Item = &'a mut T;
     type IntoIter = slice::IterMut<'a, T>;
 
@@ -163,15 +215,11 @@ impl<'a, T> IntoIterator for &'a mut Vec<T> {
     }
 }
 
-impl<A> iter::FromIterator<A> for Vec<A> {
-    fn from_iter<I: IntoIterator<Item = A>>(iter: I) -> Self {
+impl<T> iter::FromIterator<T> for Vec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
         let mut v = Vec::new();
-        let mut it = iter.into_iter();
-        loop {
-            match it.next() {
-                Some(r) => v.push(r),
-                None => break,
-            }
+        for r in iter {
+            v.push(r);
         }
         v
     }
@@ -181,16 +229,16 @@ pub trait VecAppend<E: Copy> {
     fn append(&mut self, arr: &[E]);
 }
 
-impl<T : Copy> VecAppend<T> for Vec<T> {
+impl<T : Copy, A: UsableSize> VecAppend<T> for Vec<T, A> {
     fn append(&mut self, arr: &[T]) {
         // TODO: optimize this
         for e in arr {
-            self.push(e.clone());
+            self.push(*e);
         }
     }
 }
 
-impl<T, I: SliceIndex<[T]>> Index<I> for Vec<T> {
+impl<T, A: GlobalAlloc, I: SliceIndex<[T]>> Index<I> for Vec<T, A> {
     type Output = I::Output;
 
     #[inline]
@@ -199,23 +247,25 @@ impl<T, I: SliceIndex<[T]>> Index<I> for Vec<T> {
     }
 }
 
-impl<T, I: SliceIndex<[T]>> IndexMut<I> for Vec<T> {
+impl<T, A: GlobalAlloc, I: SliceIndex<[T]>> IndexMut<I> for Vec<T, A> {
     #[inline]
     fn index_mut(&mut self, index: I) -> &mut Self::Output {
         IndexMut::index_mut(self.as_mut_slice(), index)
     }
 }
 
-impl<T> Drop for Vec<T> {
+impl<T, A: GlobalAlloc> Drop for Vec<T, A> {
     fn drop(&mut self) {
         self.drop_elements();
-        unsafe { free_array_ptr(self.elements, self.capacity) }
+        if self.capacity != 0 {
+            unsafe { free_array_ptr_in(&self.alloc, self.elements, self.capacity) }
+        }
     }
 }
 
-impl<T : Clone> Clone for Vec<T> {
+impl<T : Clone, A: UsableSize + Clone> Clone for Vec<T, A> {
     fn clone(&self) -> Self {
-        let mut c = Vec::<T>::new();
+        let mut c = Vec::<T, A>::with_capacity_in(self.count, self.alloc.clone());
         for i in 0..self.count {
             let v = self.get_unchecked(i);
             c.push(v.clone());
@@ -249,10 +299,8 @@ mod tests {
             assert!(v[i] == i);
         }
 
-        let mut counter = 0;
-        for i in v.to_iter() {
+        for (counter, i) in v.to_iter().enumerate() {
             if *i != counter { panic!("invalid {} != {}", i, counter) }
-            counter += 1;
         }
     }
     #[test]
@@ -300,4 +348,53 @@ mod tests {
 
         assert!(v.len() == 100);
     }
+
+    #[test]
+    fn test_with_capacity_in() {
+        let mut v = Vec::<i32, System>::with_capacity_in(4, System);
+        assert!(v.capacity() >= 4);
+        for i in 0..100 {
+            v.push(i);
+        }
+        let c = v.clone();
+        assert!(c.len() == 100);
+        for i in 0..100 {
+            assert!(c[i] == i as i32);
+        }
+    }
+
+    #[test]
+    fn test_try_push() {
+        let stack = StackAlloc::with_capacity(256);
+        let mut v = Vec::<u64, &StackAlloc>::new_in(&stack);
+        assert!(v.try_reserve(usize::MAX) == Err(AllocError::CapacityOverflow));
+
+        let mut err = None;
+        for i in 0..100 {
+            if let Err(e) = v.try_push(i) {
+                err = Some(e);
+                break
+            }
+        }
+        assert!(err == Some((32, AllocError::OutOfMemory(Layout::array::<u64>(64).unwrap()))));
+        assert!(v.len() == 32);
+        for i in 0..32 {
+            assert!(v[i] == i as u64);
+        }
+    }
+
+    #[test]
+    fn test_usable_capacity() {
+        let heap = SizeClassAlloc::new();
+        let mut v = Vec::<u8, _>::with_capacity_in(100, &heap);
+        assert!(v.capacity() == 112);
+        for i in 0..113 {
+            v.push(i as u8);
+        }
+        assert!(v.capacity() == 224);
+
+        let mut s = String::with_capacity_in(3, &heap);
+        s.push_str("0123456789abcdef");
+        assert!(s.as_bytes().len() == 16 && s.as_mut_vec().capacity() == 16);
+    }
 }